@@ -0,0 +1,145 @@
+//! Render [sqlweld](https://github.com/dimfeld/sqlweld) templates into string constants at
+//! compile time, instead of committing the generated SQL files.
+//!
+//! ```ignore
+//! const GET_USERS: &str = sqlweld_macros::include_query!("queries/get_users.sql.tera");
+//! ```
+//!
+//! Template paths are relative to the crate's `Cargo.toml`. Options are read from the crate's
+//! `sqlweld.toml` or `[package.metadata.sqlweld]` section, the same as when running sqlweld from
+//! the crate's directory. If these don't set an input directory, the template's directory is used.
+//!
+//! The crate is rebuilt when the template, the partials it uses, or its context and configuration
+//! files change. Adding a new partial that changes which file a name refers to is not noticed
+//! until something else triggers a rebuild.
+
+use std::path::PathBuf;
+
+use clap::ValueEnum;
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::{
+    parse::{Parse, ParseStream},
+    parse_macro_input, Ident, LitStr, Token,
+};
+
+/// The arguments to the macros: the template path, followed by optional `key = "value"` pairs.
+struct QueryArgs {
+    template: LitStr,
+    input: Option<LitStr>,
+    placeholders: Option<LitStr>,
+}
+
+impl Parse for QueryArgs {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let mut args = QueryArgs {
+            template: input.parse()?,
+            input: None,
+            placeholders: None,
+        };
+
+        while !input.is_empty() {
+            input.parse::<Token![,]>()?;
+            if input.is_empty() {
+                break;
+            }
+
+            let key: Ident = input.parse()?;
+            input.parse::<Token![=]>()?;
+            let value: LitStr = input.parse()?;
+            match key.to_string().as_str() {
+                "input" => args.input = Some(value),
+                "placeholders" => args.placeholders = Some(value),
+                _ => {
+                    return Err(syn::Error::new(
+                        key.span(),
+                        "expected `input` or `placeholders`",
+                    ))
+                }
+            }
+        }
+
+        Ok(args)
+    }
+}
+
+/// Render a `.sql.tera` template and expand to the resulting SQL as a `&'static str`.
+///
+/// The template path can be followed by `input = "dir"`, to set the directory that partials are
+/// looked up in, and `placeholders = "postgres"` to rewrite `$[name]` placeholders. Both are
+/// relative to the crate's `Cargo.toml`, and override the configuration file.
+///
+/// ```ignore
+/// const GET_USERS: &str = sqlweld_macros::sqlweld!(
+///     "queries/users/get_users.sql.tera",
+///     input = "queries",
+///     placeholders = "postgres",
+/// );
+/// ```
+#[proc_macro]
+pub fn sqlweld(input: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(input as QueryArgs);
+    match expand(&args) {
+        Ok(tokens) => tokens.into(),
+        Err(message) => syn::Error::new(args.template.span(), message)
+            .to_compile_error()
+            .into(),
+    }
+}
+
+/// The same as [sqlweld!], named to match `include_str!`.
+#[proc_macro]
+pub fn include_query(input: TokenStream) -> TokenStream {
+    sqlweld(input)
+}
+
+fn expand(args: &QueryArgs) -> Result<proc_macro2::TokenStream, String> {
+    let manifest_dir = std::env::var("CARGO_MANIFEST_DIR")
+        .map(PathBuf::from)
+        .map_err(|_| "CARGO_MANIFEST_DIR is not set".to_string())?;
+    let template = manifest_dir.join(args.template.value());
+
+    let mut builder = sqlweld::Options::builder();
+    if let Some(input) = &args.input {
+        builder = builder.input(manifest_dir.join(input.value()));
+    }
+    if let Some(placeholders) = &args.placeholders {
+        let style = sqlweld::PlaceholderStyle::from_str(&placeholders.value(), true)
+            .map_err(|e| format!("Invalid placeholder style: {e}"))?;
+        builder = builder.placeholders(style);
+    }
+
+    let options = builder
+        .build()
+        .with_config_file_from(&manifest_dir)
+        .map_err(|e| error_message(&e))?;
+    let query = sqlweld::render_query(&options, &template).map_err(|e| error_message(&e))?;
+
+    // Including the files makes cargo rebuild the crate when any of them change.
+    let files = query
+        .files
+        .iter()
+        .filter_map(|path| path.canonicalize().ok())
+        .map(|path| path.to_string_lossy().to_string());
+    let sql = query.sql;
+
+    Ok(quote! {
+        {
+            #(const _: &[u8] = include_bytes!(#files);)*
+            #sql
+        }
+    })
+}
+
+fn error_message(report: &error_stack::Report<sqlweld::Error>) -> String {
+    let diagnostics = sqlweld::report_diagnostics(report);
+    if diagnostics.is_empty() {
+        return format!("{report:?}");
+    }
+
+    diagnostics
+        .iter()
+        .map(|d| d.to_string())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
@@ -0,0 +1,33 @@
+use sqlweld_macros::{include_query, sqlweld};
+
+const GET_SOME_OBJECTS: &str = include_query!("../test_data/get_some_objects.sql.tera");
+
+#[test]
+fn include_query() {
+    assert_eq!(
+        GET_SOME_OBJECTS,
+        include_str!("../../test_data/get_some_objects.sql")
+    );
+}
+
+#[test]
+fn partial_from_parent_dir() {
+    let query = sqlweld!(
+        "../test_data/in_subdir/uses_root.sql.tera",
+        input = "../test_data",
+    );
+    assert_eq!(
+        query,
+        include_str!("../../test_data/in_subdir/uses_root.sql")
+    );
+}
+
+#[test]
+fn placeholders() {
+    let query = sqlweld!(
+        "../test_data/get_some_objects.sql.tera",
+        placeholders = "postgres"
+    );
+    assert!(query.contains("-- Parameters: obj_id, team_id, user_id"));
+    assert!(query.contains("WHERE id=$1 AND team = $2"));
+}
@@ -0,0 +1,265 @@
+use std::{path::PathBuf, time::Duration};
+
+use crate::{
+    formatter::FormatterCommand, Format, FormatterMode, KeywordCase, MessageFormat, Options,
+    PlaceholderStyle, SqlDialect,
+};
+
+impl Options {
+    /// Create a builder for [Options], for use from `build.rs` or other Rust code.
+    ///
+    /// ```no_run
+    /// let options = sqlweld::Options::builder()
+    ///     .input("queries")
+    ///     .output("generated")
+    ///     .print_rerun_if_changed(true)
+    ///     .build();
+    /// sqlweld::build(options).unwrap();
+    /// ```
+    pub fn builder() -> OptionsBuilder {
+        OptionsBuilder::default()
+    }
+}
+
+/// Builds an [Options]. Each method corresponds to the command line flag of the same name, and any
+/// option which is not set behaves the same as when its flag is omitted.
+#[derive(Debug, Default)]
+pub struct OptionsBuilder {
+    options: Options,
+}
+
+impl OptionsBuilder {
+    /// The directory to search for templates. Defaults to the current directory.
+    pub fn input(mut self, input: impl Into<PathBuf>) -> Self {
+        self.options.input = Some(input.into());
+        self
+    }
+
+    /// The directory to write output files to. Defaults to writing each output file next to its
+    /// template.
+    pub fn output(mut self, output: impl Into<PathBuf>) -> Self {
+        self.options.output = Some(output.into());
+        self
+    }
+
+    /// Add a directory to load shared partials and macro files from. Directories added earlier
+    /// take precedence when partials have the same name, and partials in the input directory take
+    /// precedence over all of them.
+    pub fn partials_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.options.partials_dirs.push(dir.into());
+        self
+    }
+
+    /// Add a partial from a string instead of a file. See [Options::add_partial_source].
+    pub fn partial_source(mut self, name: impl Into<String>, contents: impl Into<String>) -> Self {
+        self.options.add_partial_source(name, contents);
+        self
+    }
+
+    /// Extra context to pass into the templates.
+    pub fn context(mut self, context: tera::Context) -> Self {
+        self.options.context = Some(context);
+        self
+    }
+
+    /// Add a JSON, TOML, or YAML file to read template context from. Files are merged in the order
+    /// they are added.
+    pub fn context_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.options.context_files.push(path.into());
+        self
+    }
+
+    /// Set a template context value. This is parsed the same way as the `--var` flag.
+    pub fn var(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.options.vars.push((key.into(), value.into()));
+        self
+    }
+
+    /// How much to print while processing files.
+    pub fn verbose(mut self, verbose: u8) -> Self {
+        self.options.verbose = verbose;
+        self
+    }
+
+    /// Print `cargo:rerun-if-changed` statements for build.rs.
+    pub fn print_rerun_if_changed(mut self, print: bool) -> Self {
+        self.options.print_rerun_if_changed = print;
+        self
+    }
+
+    /// Traverse normally-ignored directories.
+    pub fn check_ignored_dirs(mut self, check: bool) -> Self {
+        self.options.check_ignored_dirs = check;
+        self.options.no_check_ignored_dirs = !check;
+        self
+    }
+
+    /// The header to add to generated files. An empty string disables the header.
+    pub fn header(mut self, header: impl Into<String>) -> Self {
+        self.options.header = Some(header.into());
+        self
+    }
+
+    /// The extension of the generated files. Defaults to `sql`.
+    pub fn extension(mut self, extension: impl Into<String>) -> Self {
+        self.options.extension = Some(extension.into());
+        self
+    }
+
+    /// Write output files even when their contents have not changed.
+    pub fn always_write(mut self, always_write: bool) -> Self {
+        self.options.always_write = always_write;
+        self.options.no_always_write = !always_write;
+        self
+    }
+
+    /// A command that formats SQL from stdin to stdout. The command is split into arguments the
+    /// way a shell would.
+    pub fn formatter(mut self, formatter: impl Into<String>) -> Self {
+        self.options.formatter = Some(FormatterCommand::Shell(formatter.into()));
+        self
+    }
+
+    /// Like [OptionsBuilder::formatter], but with the program and its arguments given separately.
+    pub fn formatter_args(mut self, args: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.options.formatter = Some(FormatterCommand::Args(
+            args.into_iter().map(|arg| arg.into()).collect(),
+        ));
+        self
+    }
+
+    /// How the formatter receives the SQL. In batch mode it is run once with the paths of all the
+    /// files, and formats them in place.
+    pub fn formatter_mode(mut self, mode: FormatterMode) -> Self {
+        self.options.formatter_mode = Some(mode);
+        self
+    }
+
+    /// Stop the formatter and fail if it runs for longer than this.
+    pub fn formatter_timeout(mut self, timeout: Duration) -> Self {
+        self.options.formatter_timeout = Some(timeout);
+        self
+    }
+
+    /// Pass this environment variable to the formatter. When any are given, the formatter only
+    /// receives these variables and `PATH`.
+    pub fn formatter_env(mut self, name: impl Into<String>) -> Self {
+        self.options.formatter_env.push(name.into());
+        self
+    }
+
+    /// The SQL dialect which replaces `{dialect}` in the formatter command.
+    pub fn dialect(mut self, dialect: SqlDialect) -> Self {
+        self.options.dialect = Some(dialect);
+        self
+    }
+
+    /// Format the SQL with the built-in formatter instead of a command.
+    pub fn format(mut self, format: Format) -> Self {
+        self.options.format = Some(format);
+        self
+    }
+
+    /// The number of spaces for each level of indentation in the built-in formatter.
+    pub fn format_indent(mut self, indent: usize) -> Self {
+        self.options.format_indent = Some(indent);
+        self
+    }
+
+    /// How the built-in formatter writes SQL keywords.
+    pub fn keyword_case(mut self, case: KeywordCase) -> Self {
+        self.options.keyword_case = Some(case);
+        self
+    }
+
+    /// The line width that the built-in formatter tries to keep within.
+    pub fn line_width(mut self, width: usize) -> Self {
+        self.options.line_width = Some(width);
+        self
+    }
+
+    /// Rewrite `$[name]` placeholders into this style.
+    pub fn placeholders(mut self, style: PlaceholderStyle) -> Self {
+        self.options.placeholders = Some(style);
+        self
+    }
+
+    /// Check that each generated query parses as SQL in this dialect.
+    pub fn validate(mut self, dialect: SqlDialect) -> Self {
+        self.options.validate = Some(dialect);
+        self
+    }
+
+    /// Write a JSON manifest next to each generated file.
+    pub fn manifest(mut self, manifest: bool) -> Self {
+        self.options.manifest = manifest;
+        self.options.no_manifest = !manifest;
+        self
+    }
+
+    /// Write a Makefile-style dependency file to this path.
+    pub fn depfile(mut self, path: impl Into<PathBuf>) -> Self {
+        self.options.depfile = Some(path.into());
+        self
+    }
+
+    /// Write a Rust module with a constant for each query to this path.
+    pub fn rust_module(mut self, path: impl Into<PathBuf>) -> Self {
+        self.options.rust_module = Some(path.into());
+        self
+    }
+
+    /// Keep running after the initial build and re-render templates when they change.
+    pub fn watch(mut self, watch: bool) -> Self {
+        self.options.watch = watch;
+        self
+    }
+
+    /// Verify that the output files are up to date instead of writing them.
+    pub fn check(mut self, check: bool) -> Self {
+        self.options.check = check;
+        self
+    }
+
+    /// Render every template even if some fail, and report all the failures together.
+    pub fn keep_going(mut self, keep_going: bool) -> Self {
+        self.options.keep_going = keep_going;
+        self
+    }
+
+    /// Remove generated files whose templates no longer exist.
+    pub fn prune(mut self, prune: bool) -> Self {
+        self.options.prune = prune;
+        self.options.no_prune = !prune;
+        self
+    }
+
+    /// When pruning, only list the files that would be removed.
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.options.dry_run = dry_run;
+        self
+    }
+
+    /// The configuration file to use with [Options::with_config_file].
+    pub fn config(mut self, path: impl Into<PathBuf>) -> Self {
+        self.options.config = Some(path.into());
+        self
+    }
+
+    /// Make [Options::with_config_file] skip reading configuration files.
+    pub fn no_config(mut self, no_config: bool) -> Self {
+        self.options.no_config = no_config;
+        self
+    }
+
+    /// How errors and warnings should be printed in watch mode. Otherwise [build](crate::build)
+    /// returns its errors and warnings instead of printing them.
+    pub fn message_format(mut self, format: MessageFormat) -> Self {
+        self.options.message_format = format;
+        self
+    }
+
+    pub fn build(self) -> Options {
+        self.options
+    }
+}
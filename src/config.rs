@@ -0,0 +1,171 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use error_stack::{Report, ResultExt};
+use serde::Deserialize;
+
+use crate::{
+    diagnostics::print_status, formatter::FormatterCommand, Error, Format, FormatterMode,
+    KeywordCase, Options, PlaceholderStyle, SqlDialect,
+};
+
+pub(crate) const CONFIG_FILE_NAME: &str = "sqlweld.toml";
+
+/// The settings that can be given in `sqlweld.toml` or in the `[package.metadata.sqlweld]`
+/// section of `Cargo.toml`. The keys match the command line flags.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct ConfigFile {
+    input: Option<PathBuf>,
+    output: Option<PathBuf>,
+    partials_dirs: Vec<PathBuf>,
+    header: Option<String>,
+    ext: Option<String>,
+    formatter: Option<FormatterCommand>,
+    formatter_mode: Option<FormatterMode>,
+    formatter_timeout: Option<String>,
+    formatter_env: Vec<String>,
+    dialect: Option<SqlDialect>,
+    format: Option<Format>,
+    format_indent: Option<usize>,
+    keyword_case: Option<KeywordCase>,
+    line_width: Option<usize>,
+    always_write: bool,
+    check_ignored_dirs: bool,
+    placeholders: Option<PlaceholderStyle>,
+    validate: Option<SqlDialect>,
+    manifest: bool,
+    rust_module: Option<PathBuf>,
+    depfile: Option<PathBuf>,
+    prune: bool,
+    context_files: Vec<PathBuf>,
+    vars: BTreeMap<String, serde_json::Value>,
+}
+
+/// Look for a configuration file in `start` and each of its parent directories. In each
+/// directory, `sqlweld.toml` is preferred over a `Cargo.toml` with sqlweld metadata.
+pub(crate) fn find_config_file(start: &Path) -> Result<Option<PathBuf>, Report<Error>> {
+    for dir in start.ancestors() {
+        let config_path = dir.join(CONFIG_FILE_NAME);
+        if config_path.is_file() {
+            return Ok(Some(config_path));
+        }
+
+        let cargo_path = dir.join("Cargo.toml");
+        if cargo_path.is_file() && cargo_metadata(&cargo_path)?.is_some() {
+            return Ok(Some(cargo_path));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Read the `[package.metadata.sqlweld]` section from a Cargo.toml file, if it has one.
+fn cargo_metadata(path: &Path) -> Result<Option<toml::Value>, Report<Error>> {
+    let mut manifest = read_toml(path)?;
+    let metadata = manifest
+        .get_mut("package")
+        .and_then(|p| p.get_mut("metadata"))
+        .and_then(|m| m.as_table_mut())
+        .and_then(|m| m.remove("sqlweld"));
+    Ok(metadata)
+}
+
+fn read_toml(path: &Path) -> Result<toml::Value, Report<Error>> {
+    let contents = std::fs::read_to_string(path)
+        .change_context(Error::Config)
+        .attach_printable_lazy(|| path.display().to_string())?;
+
+    toml::from_str(&contents)
+        .change_context(Error::Config)
+        .attach_printable_lazy(|| path.display().to_string())
+}
+
+fn read_config_file(path: &Path) -> Result<ConfigFile, Report<Error>> {
+    let value = if path.file_name().map(|f| f == "Cargo.toml").unwrap_or(false) {
+        cargo_metadata(path)?.unwrap_or_else(|| toml::Value::Table(Default::default()))
+    } else {
+        read_toml(path)?
+    };
+
+    ConfigFile::deserialize(value)
+        .change_context(Error::Config)
+        .attach_printable_lazy(|| path.display().to_string())
+}
+
+/// Fill in the options that were not set from the configuration file at `path`. Relative paths
+/// in the file are relative to the directory containing it.
+pub(crate) fn apply_config_file(
+    mut options: Options,
+    path: &Path,
+) -> Result<Options, Report<Error>> {
+    let config = read_config_file(path)?;
+    let base_dir = path.parent().unwrap_or(Path::new(""));
+
+    if options.verbose >= 2 {
+        print_status(
+            options.message_format,
+            format!("Using configuration from {}", path.display()),
+        );
+    }
+
+    options.input = options.input.or(config.input.map(|p| base_dir.join(p)));
+    options.output = options.output.or(config.output.map(|p| base_dir.join(p)));
+    options.rust_module = options
+        .rust_module
+        .or(config.rust_module.map(|p| base_dir.join(p)));
+    options.depfile = options.depfile.or(config.depfile.map(|p| base_dir.join(p)));
+    options.header = options.header.or(config.header);
+    options.extension = options.extension.or(config.ext);
+    // A formatter chosen on the command line replaces the one from the file, whichever kind it is.
+    if options.formatter.is_none() && options.format.is_none() {
+        options.formatter = config.formatter;
+        options.format = config.format;
+    }
+    options.formatter_mode = options.formatter_mode.or(config.formatter_mode);
+    if options.formatter_timeout.is_none() {
+        options.formatter_timeout = config
+            .formatter_timeout
+            .map(|timeout| humantime::parse_duration(&timeout))
+            .transpose()
+            .change_context(Error::Config)
+            .attach_printable_lazy(|| path.display().to_string())?;
+    }
+    if options.formatter_env.is_empty() {
+        options.formatter_env = config.formatter_env;
+    }
+    options.dialect = options.dialect.or(config.dialect);
+    options.format_indent = options.format_indent.or(config.format_indent);
+    options.keyword_case = options.keyword_case.or(config.keyword_case);
+    options.line_width = options.line_width.or(config.line_width);
+    options.placeholders = options.placeholders.or(config.placeholders);
+    options.validate = options.validate.or(config.validate);
+    // Switches which are on in the file can be turned off with their `--no-` flags.
+    options.always_write |= config.always_write && !options.no_always_write;
+    options.check_ignored_dirs |= config.check_ignored_dirs && !options.no_check_ignored_dirs;
+    options.manifest |= config.manifest && !options.no_manifest;
+    options.prune |= config.prune && !options.no_prune;
+
+    if options.partials_dirs.is_empty() {
+        options.partials_dirs = config
+            .partials_dirs
+            .into_iter()
+            .map(|p| base_dir.join(p))
+            .collect();
+    }
+
+    // Values from the config file come first so that the ones from the command line override them.
+    let mut context_files = config
+        .context_files
+        .into_iter()
+        .map(|p| base_dir.join(p))
+        .collect::<Vec<_>>();
+    context_files.append(&mut options.context_files);
+    options.context_files = context_files;
+
+    options.config_vars.extend(config.vars);
+
+    Ok(options)
+}
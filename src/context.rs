@@ -0,0 +1,166 @@
+use std::path::Path;
+
+use error_stack::{Report, ResultExt};
+use serde_json::{Map, Value};
+
+use crate::{Error, Options};
+
+/// Build the context that templates are rendered with. Context files are merged in order, then
+/// the context passed in [Options::context](crate::Options), and finally the `vars` from the
+/// configuration file and the `--var` values.
+pub(crate) fn build_context(options: &Options) -> Result<tera::Context, Report<Error>> {
+    let mut merged = Value::Object(Map::new());
+
+    for path in &options.context_files {
+        let value = read_context_file(path)?;
+        merge(&mut merged, value, "")
+            .attach_printable_lazy(|| format!("While merging {}", path.display()))?;
+    }
+
+    if let Some(context) = options.context.clone() {
+        merge_override(&mut merged, context.into_json());
+    }
+
+    let vars = options
+        .config_vars
+        .iter()
+        .map(|(key, value)| (key, value.clone()))
+        .chain(
+            options
+                .vars
+                .iter()
+                .map(|(key, value)| (key, parse_var_value(value))),
+        );
+    for (key, mut value) in vars {
+        for part in key.rsplit('.') {
+            let mut object = Map::new();
+            object.insert(part.to_string(), value);
+            value = Value::Object(object);
+        }
+
+        merge_override(&mut merged, value);
+    }
+
+    tera::Context::from_value(merged).change_context(Error::InternalError)
+}
+
+/// Parse a `key=value` argument.
+pub(crate) fn parse_var(arg: &str) -> Result<(String, String), String> {
+    let (key, value) = arg
+        .split_once('=')
+        .ok_or_else(|| format!("expected key=value, found `{arg}`"))?;
+
+    if key.is_empty() || key.split('.').any(|part| part.is_empty()) {
+        return Err(format!("invalid variable name `{key}`"));
+    }
+
+    Ok((key.to_string(), value.to_string()))
+}
+
+/// Values which look like JSON are parsed as JSON. Anything else is treated as a string.
+fn parse_var_value(value: &str) -> Value {
+    serde_json::from_str(value).unwrap_or_else(|_| Value::String(value.to_string()))
+}
+
+fn read_context_file(path: &Path) -> Result<Value, Report<Error>> {
+    let contents = std::fs::read_to_string(path)
+        .change_context(Error::ReadContext)
+        .attach_printable_lazy(|| path.display().to_string())?;
+
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+
+    let value = match extension.as_str() {
+        "json" => serde_json::from_str::<Value>(&contents).change_context(Error::ReadContext),
+        "toml" => toml::from_str::<Value>(&contents).change_context(Error::ReadContext),
+        "yaml" | "yml" => {
+            serde_yaml::from_str::<Value>(&contents).change_context(Error::ReadContext)
+        }
+        _ => Err(Report::new(Error::ReadContext))
+            .attach_printable("Context files must end in .json, .toml, .yaml, or .yml"),
+    }
+    .attach_printable_lazy(|| path.display().to_string())?;
+
+    if !value.is_object() {
+        return Err(Report::new(Error::ReadContext))
+            .attach_printable("The top level of a context file must be an object")
+            .attach_printable(path.display().to_string());
+    }
+
+    Ok(value)
+}
+
+/// Merge `source` into `target`. Objects are merged recursively, and any other value replaces the
+/// existing value as long as they have the same type.
+pub(crate) fn merge(
+    target: &mut Value,
+    source: Value,
+    key_path: &str,
+) -> Result<(), Report<Error>> {
+    match (target, source) {
+        (Value::Object(target), Value::Object(source)) => {
+            for (key, value) in source {
+                let child_path = if key_path.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{key_path}.{key}")
+                };
+
+                match target.get_mut(&key) {
+                    Some(existing) => merge(existing, value, &child_path)?,
+                    None => {
+                        target.insert(key, value);
+                    }
+                }
+            }
+        }
+        (target, source) => {
+            let compatible = target.is_null()
+                || source.is_null()
+                || std::mem::discriminant(target) == std::mem::discriminant(&source);
+            if !compatible {
+                return Err(Report::new(Error::ContextConflict)).attach_printable(format!(
+                    "`{key_path}` is {} in one place and {} in another",
+                    type_name(target),
+                    type_name(&source)
+                ));
+            }
+
+            *target = source;
+        }
+    }
+
+    Ok(())
+}
+
+/// Merge `source` into `target` like [merge], but let values replace existing values of any type.
+/// This is used for values set directly, which should always win over the context files.
+fn merge_override(target: &mut Value, source: Value) {
+    match (target, source) {
+        (Value::Object(target), Value::Object(source)) => {
+            for (key, value) in source {
+                match target.get_mut(&key) {
+                    Some(existing) => merge_override(existing, value),
+                    None => {
+                        target.insert(key, value);
+                    }
+                }
+            }
+        }
+        (target, source) => *target = source,
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "a boolean",
+        Value::Number(_) => "a number",
+        Value::String(_) => "a string",
+        Value::Array(_) => "an array",
+        Value::Object(_) => "an object",
+    }
+}
@@ -0,0 +1,64 @@
+use std::collections::BTreeSet;
+
+use tera::{ast::Node, Tera};
+
+/// Find the names of all the templates that `name` uses through `extends`, `import`, or `include`,
+/// including those used indirectly. Templates that don't exist are still returned, so that
+/// callers can tell when a missing template is added back.
+pub(crate) fn template_dependencies(tera: &Tera, name: &str) -> BTreeSet<String> {
+    let mut found = BTreeSet::new();
+    let mut pending = vec![name.to_string()];
+
+    while let Some(current) = pending.pop() {
+        let Some(template) = tera.templates.get(&current) else {
+            continue;
+        };
+
+        let mut direct = Vec::new();
+        direct.extend(template.parent.iter().cloned());
+        direct.extend(
+            template
+                .imported_macro_files
+                .iter()
+                .map(|(file, _)| file.clone()),
+        );
+        find_includes(&template.ast, &mut direct);
+        for definition in template.macros.values() {
+            find_includes(&definition.body, &mut direct);
+        }
+
+        for dep in direct {
+            if dep != name && found.insert(dep.clone()) {
+                pending.push(dep);
+            }
+        }
+    }
+
+    found
+}
+
+fn find_includes(nodes: &[Node], found: &mut Vec<String>) {
+    for node in nodes {
+        match node {
+            Node::Include(_, names, _) => found.extend(names.iter().cloned()),
+            Node::MacroDefinition(_, definition, _) => find_includes(&definition.body, found),
+            Node::FilterSection(_, section, _) => find_includes(&section.body, found),
+            Node::Block(_, block, _) => find_includes(&block.body, found),
+            Node::Forloop(_, forloop, _) => {
+                find_includes(&forloop.body, found);
+                if let Some(body) = &forloop.empty_body {
+                    find_includes(body, found);
+                }
+            }
+            Node::If(condition, _) => {
+                for (_, _, body) in &condition.conditions {
+                    find_includes(body, found);
+                }
+                if let Some((_, body)) = &condition.otherwise {
+                    find_includes(body, found);
+                }
+            }
+            _ => {}
+        }
+    }
+}
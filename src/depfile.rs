@@ -0,0 +1,72 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use error_stack::Report;
+use itertools::Itertools;
+
+use crate::{sync_file, Error, Options, OutputFile, TemplateReport};
+
+/// Escape a path for use in a Makefile rule.
+fn escape(path: &std::path::Path) -> String {
+    let path = path.to_string_lossy();
+    let mut escaped = String::with_capacity(path.len());
+    for c in path.chars() {
+        match c {
+            ' ' => escaped.push_str("\\ "),
+            '#' => escaped.push_str("\\#"),
+            '$' => escaped.push_str("$$"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Generate a Makefile-style dependency file with a rule for each output file, listing the files
+/// it was rendered from. The Rust module, if any, depends on the inputs of every template.
+pub(crate) fn depfile<'a>(
+    rendered: impl IntoIterator<Item = &'a TemplateReport>,
+    rust_module: &Option<OutputFile>,
+) -> String {
+    let mut rules = BTreeMap::new();
+    let mut all_inputs = BTreeSet::new();
+    for template in rendered {
+        let inputs = template.inputs.iter().collect::<BTreeSet<_>>();
+        all_inputs.extend(inputs.iter().copied());
+        for output in &template.outputs {
+            rules.insert(&output.path, inputs.clone());
+        }
+    }
+
+    if let Some(rust_module) = rust_module {
+        rules.insert(&rust_module.path, all_inputs);
+    }
+
+    rules
+        .into_iter()
+        .map(|(target, inputs)| {
+            format!(
+                "{}: {}\n",
+                escape(target),
+                inputs.into_iter().map(|p| escape(p)).join(" ")
+            )
+        })
+        .collect()
+}
+
+/// Write the dependency file to [Options::depfile], if it is set. Nothing is written in check
+/// mode.
+pub(crate) fn write_depfile<'a>(
+    options: &Options,
+    rendered: impl IntoIterator<Item = &'a TemplateReport>,
+    rust_module: &Option<OutputFile>,
+) -> Result<Option<OutputFile>, Report<Error>> {
+    let Some(path) = options.depfile.as_ref() else {
+        return Ok(None);
+    };
+
+    if options.check {
+        return Ok(None);
+    }
+
+    let contents = depfile(rendered, rust_module);
+    sync_file(options, path.clone(), &contents).map(Some)
+}
@@ -0,0 +1,709 @@
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use error_stack::{AttachmentKind, Frame, FrameKind, Report};
+use itertools::Itertools;
+
+use crate::{
+    dependencies::template_dependencies, validate::SqlError, Error, StaleFiles, TemplateSet,
+    TestCaseReport,
+};
+
+/// How errors and warnings are printed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum MessageFormat {
+    /// Readable text on stderr.
+    #[default]
+    Human,
+    /// One JSON object per line on stdout.
+    Json,
+    /// A SARIF 2.1.0 log on stdout.
+    Sarif,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+/// A description of a template error, pointing to the place in the source file where it happened
+/// when that can be determined. This is attached to the error reports returned by
+/// [build](crate::build), and can be retrieved from them with `Frame::downcast_ref`.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// A short identifier for the kind of problem, such as `render` or `unused-partial`.
+    pub code: &'static str,
+    /// The underlying cause of the error.
+    pub message: String,
+    /// Where the error happened.
+    pub location: Option<SourceLocation>,
+    /// The chain of templates, parents, and macro calls that led to the error, outermost first.
+    pub notes: Vec<String>,
+    #[serde(skip)]
+    snippet: Option<String>,
+}
+
+/// The file where an error happened and, when it could be found, the position in that file.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct SourceLocation {
+    pub path: PathBuf,
+    pub span: Option<Span>,
+}
+
+/// A range of text on a single line. Lines and columns start at 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+pub struct Span {
+    pub line: usize,
+    pub column: usize,
+    /// The number of characters in the range.
+    pub length: usize,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+
+        match self.location.as_ref().map(|l| (&l.path, l.span)) {
+            Some((path, Some(span))) => {
+                let gutter = " ".repeat(span.line.to_string().len());
+                write!(
+                    f,
+                    "\n{gutter}--> {}:{}:{}",
+                    path.display(),
+                    span.line,
+                    span.column
+                )?;
+
+                if let Some(snippet) = &self.snippet {
+                    let padding = " ".repeat(span.column.saturating_sub(1));
+                    let carets = "^".repeat(span.length.max(1));
+                    write!(
+                        f,
+                        "\n{gutter} |\n{} | {snippet}\n{gutter} | {padding}{carets}",
+                        span.line
+                    )?;
+                }
+            }
+            Some((path, None)) => write!(f, "\n--> {}", path.display())?,
+            None => {}
+        }
+
+        for note in &self.notes {
+            write!(f, "\n  = note: {note}")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Diagnostic {
+    fn new(error: &Error, message: String) -> Self {
+        Diagnostic {
+            severity: Severity::Error,
+            code: error.code(),
+            message,
+            location: None,
+            notes: Vec::new(),
+            snippet: None,
+        }
+    }
+
+    fn with_location(mut self, path: &Path, source: &str, offset: usize, length: usize) -> Self {
+        let (line, column) = line_and_column(source, offset);
+        self.snippet = source.lines().nth(line - 1).map(|l| l.to_string());
+        self.location = Some(SourceLocation {
+            path: path.to_path_buf(),
+            span: Some(Span {
+                line,
+                column,
+                length,
+            }),
+        });
+        self
+    }
+
+    pub(crate) fn warning(code: &'static str, message: String, path: &Path) -> Self {
+        Diagnostic {
+            severity: Severity::Warning,
+            code,
+            message,
+            location: None,
+            notes: Vec::new(),
+            snippet: None,
+        }
+        .with_path(path)
+    }
+
+    fn with_path(mut self, path: &Path) -> Self {
+        self.location = Some(SourceLocation {
+            path: path.to_path_buf(),
+            span: None,
+        });
+        self
+    }
+}
+
+/// The messages of a Tera error and all of its sources, outermost first.
+fn error_chain(error: &tera::Error) -> Vec<String> {
+    let mut messages = vec![error.to_string()];
+    let mut source = std::error::Error::source(error);
+    while let Some(e) = source {
+        messages.push(e.to_string());
+        source = e.source();
+    }
+    messages
+}
+
+/// The text between the first occurrence of `start` and the following `end`.
+fn between<'a>(s: &'a str, start: &str, end: char) -> Option<&'a str> {
+    let rest = &s[s.find(start)? + start.len()..];
+    Some(&rest[..rest.find(end)?])
+}
+
+fn line_and_column(source: &str, offset: usize) -> (usize, usize) {
+    let before = &source[..offset];
+    let line = before.matches('\n').count() + 1;
+    let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+    let column = before[line_start..].chars().count() + 1;
+    (line, column)
+}
+
+fn offset_of(source: &str, line: usize, column: usize) -> Option<usize> {
+    let line_start = if line <= 1 {
+        0
+    } else {
+        source.match_indices('\n').nth(line - 2)?.0 + 1
+    };
+
+    let line_text = &source[line_start..];
+    let column_offset = line_text
+        .char_indices()
+        .nth(column.saturating_sub(1))
+        .map(|(i, _)| i)
+        .unwrap_or(0);
+    Some(line_start + column_offset)
+}
+
+fn is_identifier_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// The byte ranges of the `{{ }}` and `{% %}` tags in a template.
+fn tag_ranges(source: &str) -> Vec<(usize, usize)> {
+    let mut ranges = Vec::new();
+    let mut pos = 0;
+    while let Some(start) = source[pos..].find(['{']).map(|i| i + pos) {
+        let close = match source[start + 1..].chars().next() {
+            Some('{') => "}}",
+            Some('%') => "%}",
+            _ => {
+                pos = start + 1;
+                continue;
+            }
+        };
+
+        let end = source[start..]
+            .find(close)
+            .map(|i| start + i + close.len())
+            .unwrap_or(source.len());
+        ranges.push((start, end));
+        pos = end;
+    }
+    ranges
+}
+
+/// Find `token` inside a template tag, looking only within `range`.
+fn find_in_tags(source: &str, token: &str, range: (usize, usize)) -> Option<usize> {
+    if token.is_empty() {
+        return None;
+    }
+
+    let tags = tag_ranges(source);
+    source[range.0..range.1]
+        .match_indices(token)
+        .map(|(i, _)| range.0 + i)
+        .find(|&offset| {
+            let before_ok = source[..offset]
+                .chars()
+                .next_back()
+                .map(|c| !is_identifier_char(c))
+                .unwrap_or(true);
+            let after_ok = source[offset + token.len()..]
+                .chars()
+                .next()
+                .map(|c| !is_identifier_char(c))
+                .unwrap_or(true);
+            let in_tag = tags
+                .iter()
+                .any(|(start, end)| *start <= offset && offset < *end);
+            before_ok && after_ok && in_tag
+        })
+}
+
+/// The byte range of a macro definition in a template.
+fn macro_range(source: &str, macro_name: &str) -> Option<(usize, usize)> {
+    let tags = tag_ranges(source);
+    let start = tags.iter().find(|(start, end)| {
+        let tag = &source[*start..*end];
+        tag.contains("macro")
+            && tag
+                .split(|c: char| !is_identifier_char(c))
+                .any(|word| word == macro_name)
+    })?;
+    let end = tags
+        .iter()
+        .find(|(s, end)| *s > start.0 && source[*s..*end].contains("endmacro"))
+        .map(|(_, end)| *end)
+        .unwrap_or(source.len());
+    Some((start.0, end))
+}
+
+impl TemplateSet {
+    pub(crate) fn template_path(&self, name: &str) -> Option<&Path> {
+        self.files
+            .iter()
+            .find(|(_, n)| n.as_str() == name)
+            .map(|(path, _)| path.as_path())
+    }
+
+    fn template_source(&self, name: &str) -> Option<(&Path, String)> {
+        let path = self.template_path(name)?;
+        let source = self.read_source(path).ok()?;
+        Some((path, source))
+    }
+
+    /// Find the template that a macro namespace refers to, as seen from `template`.
+    fn macro_file(&self, template: &str, namespace: &str) -> Option<String> {
+        let tpl = self.tera.templates.get(template)?;
+        std::iter::once(template)
+            .chain(tpl.parents.iter().map(|p| p.as_str()))
+            .filter_map(|name| self.tera.templates.get(name))
+            .flat_map(|t| t.imported_macro_files.iter())
+            .find(|(_, ns)| ns == namespace)
+            .map(|(file, _)| file.clone())
+    }
+}
+
+/// Describe an error that happened while rendering the template `name`.
+pub(crate) fn render_diagnostic(
+    templates: &TemplateSet,
+    name: &str,
+    error: &tera::Error,
+) -> Diagnostic {
+    let chain = error_chain(error);
+    let outer = chain.first().map(|s| s.as_str()).unwrap_or_default();
+    let root = chain.last().cloned().unwrap_or_default();
+    let mut diagnostic = Diagnostic::new(&Error::Render, root.clone());
+
+    let macro_call = between(outer, "error while rendering macro `", '`')
+        .and_then(|m| m.split_once("::"))
+        .map(|(ns, m)| (ns.to_string(), m.to_string()));
+    let macro_file = macro_call
+        .as_ref()
+        .and_then(|(ns, _)| templates.macro_file(name, ns));
+
+    // Figure out which template the error actually happened in. Inside a macro, the innermost
+    // error names the macro's template. Otherwise the outer error tells us if it was in a parent.
+    let active_template = between(&root, "while rendering '", '\'');
+    let parent_template = between(outer, "(error happened in '", '\'');
+    let culprit = if macro_call.is_some() {
+        active_template
+            .map(|s| s.to_string())
+            .or_else(|| macro_file.clone())
+    } else {
+        parent_template.or(active_template).map(|s| s.to_string())
+    }
+    .unwrap_or_else(|| name.to_string());
+
+    if let Some(tpl) = templates.tera.templates.get(name) {
+        if !tpl.parents.is_empty() {
+            diagnostic.notes.push(format!(
+                "`{name}` extends {}",
+                tpl.parents.iter().map(|p| format!("`{p}`")).join(", ")
+            ));
+        }
+    }
+
+    if let (Some((ns, macro_name)), Some(file)) = (&macro_call, &macro_file) {
+        let mut note = format!("error happened in macro `{ns}::{macro_name}` from `{file}`");
+        if let Some(path) = templates.template_path(file) {
+            note = format!("{note} ({})", path.display());
+        }
+        let call = format!("{ns}::{macro_name}");
+        let call_site = std::iter::once(name.to_string())
+            .chain(
+                templates
+                    .tera
+                    .templates
+                    .get(name)
+                    .map(|t| t.parents.clone())
+                    .unwrap_or_default(),
+            )
+            .find_map(|tpl_name| {
+                let (path, source) = templates.template_source(&tpl_name)?;
+                let offset = find_in_tags(&source, &call, (0, source.len()))?;
+                let (line, column) = line_and_column(&source, offset);
+                Some(format!("{}:{line}:{column}", path.display()))
+            });
+        if let Some(call_site) = call_site {
+            note.push_str(&format!(", called at {call_site}"));
+        }
+        diagnostic.notes.push(note);
+    } else if culprit != name {
+        diagnostic.notes.push(format!(
+            "error happened in `{culprit}` while rendering `{name}`"
+        ));
+    }
+
+    let Some((path, source)) = templates.template_source(&culprit) else {
+        return diagnostic;
+    };
+
+    let search_range = macro_call
+        .as_ref()
+        .filter(|_| macro_file.as_deref() == Some(culprit.as_str()))
+        .and_then(|(_, m)| macro_range(&source, m))
+        .unwrap_or((0, source.len()));
+
+    // The thing that went wrong is usually quoted in the error message.
+    let token = between(&root, "`", '`')
+        .or_else(|| between(&root, "'", '\''))
+        .filter(|t| *t != culprit);
+    let offset = token.and_then(|token| {
+        let token = token.split(['.', '[']).next().unwrap_or(token);
+        find_in_tags(&source, token, search_range).map(|offset| (offset, token.chars().count()))
+    });
+
+    match offset {
+        Some((offset, length)) => diagnostic.with_location(path, &source, offset, length),
+        None => diagnostic.with_path(path),
+    }
+}
+
+/// Describe a parse error in the SQL generated from the template `name`. The location points into
+/// the output file, and when the line with the error appears as-is in exactly one of the template
+/// files that were used, a note points to it there.
+pub(crate) fn sql_diagnostic(
+    templates: &TemplateSet,
+    name: &str,
+    output_path: &Path,
+    sql: &str,
+    error: &SqlError,
+) -> Diagnostic {
+    let mut diagnostic = Diagnostic::new(&Error::InvalidSql, error.message.clone());
+    let length = error.found.as_ref().map(|f| f.chars().count()).unwrap_or(1);
+    let offset = error
+        .position
+        .and_then(|(line, column)| offset_of(sql, line, column));
+
+    diagnostic = match offset {
+        Some(offset) => diagnostic.with_location(output_path, sql, offset, length),
+        None => diagnostic.with_path(output_path),
+    };
+
+    if let Some(path) = templates.template_path(name) {
+        diagnostic
+            .notes
+            .push(format!("generated from {}", path.display()));
+    }
+
+    let Some(offset) = offset else {
+        return diagnostic;
+    };
+
+    let line_start = sql[..offset].rfind('\n').map(|i| i + 1).unwrap_or(0);
+    let line_end = sql[offset..]
+        .find('\n')
+        .map(|i| offset + i)
+        .unwrap_or(sql.len());
+    let line = &sql[line_start..line_end];
+    let trimmed = line.trim();
+    let lead = line.len() - line.trim_start().len();
+    if trimmed.is_empty() || offset - line_start < lead {
+        return diagnostic;
+    }
+
+    let files = std::iter::once(name.to_string())
+        .chain(template_dependencies(&templates.tera, name))
+        .filter_map(|tpl| templates.template_source(&tpl))
+        .collect::<Vec<_>>();
+    let matches = files
+        .iter()
+        .flat_map(|(path, source)| {
+            source
+                .match_indices(trimmed)
+                .map(move |(i, _)| (*path, source, i))
+        })
+        .collect::<Vec<_>>();
+
+    if let [(path, source, found)] = matches.as_slice() {
+        let (line, column) = line_and_column(source, found + (offset - line_start - lead));
+        diagnostic
+            .notes
+            .push(format!("from {}:{line}:{column}", path.display()));
+    }
+
+    diagnostic
+}
+
+/// Describe an error that happened while reading and parsing the templates.
+pub(crate) fn load_diagnostic(templates: &TemplateSet, error: &tera::Error) -> Diagnostic {
+    let chain = error_chain(error);
+    let root = chain.last().cloned().unwrap_or_default();
+
+    // Parse errors look like this:
+    //  --> 3:8
+    //   |
+    // 3 | {{ a b }}
+    //   |      ^---
+    //   |
+    //   = expected ...
+    let position = between(&root, "--> ", '\n').and_then(|pos| {
+        let (line, column) = pos.trim().split_once(':')?;
+        Some((line.parse::<usize>().ok()?, column.parse::<usize>().ok()?))
+    });
+
+    let message = root
+        .lines()
+        .find_map(|l| l.trim().strip_prefix("= "))
+        .map(|m| m.to_string())
+        .unwrap_or_else(|| root.trim().to_string());
+    let mut diagnostic = Diagnostic::new(&Error::ReadTemplate, message);
+    diagnostic.notes.extend(
+        chain
+            .iter()
+            .take(chain.len().saturating_sub(1))
+            .map(|m| m.to_string()),
+    );
+
+    let path = chain.iter().find_map(|message| {
+        templates
+            .files
+            .keys()
+            .find(|path| message.contains(&format!("{:?}", path)))
+    });
+
+    match (path, position) {
+        (Some(path), Some((line, column))) => {
+            let Ok(source) = templates.read_source(path) else {
+                return diagnostic;
+            };
+            match offset_of(&source, line, column) {
+                Some(offset) => diagnostic.with_location(path, &source, offset, 1),
+                None => diagnostic,
+            }
+        }
+        (Some(path), None) => diagnostic.with_path(path),
+        _ => diagnostic,
+    }
+}
+
+/// A diagnostic for a template test case that failed.
+pub(crate) fn test_diagnostic(case: &TestCaseReport) -> Diagnostic {
+    let mut diagnostic =
+        Diagnostic::new(&Error::TestFailed, format!("Test `{}` failed", case.name))
+            .with_path(&case.test_file);
+    diagnostic
+        .notes
+        .push(format!("rendering {}", case.template.display()));
+    diagnostic.notes.extend(case.failures.iter().cloned());
+    diagnostic
+}
+
+/// Convert an error returned by [build](crate::build) or [clean](crate::clean) into diagnostics.
+/// A report that combines several failures, such as from [Options::keep_going](crate::Options),
+/// produces one diagnostic for each failure.
+pub fn report_diagnostics(report: &Report<Error>) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    collect_diagnostics(report.current_frames(), &mut Vec::new(), &mut diagnostics);
+    diagnostics
+}
+
+/// Walk the frames of a report. `attachments` holds the attachments seen since the last [Error].
+fn collect_diagnostics<'a>(
+    frames: &'a [Frame],
+    attachments: &mut Vec<&'a Frame>,
+    diagnostics: &mut Vec<Diagnostic>,
+) {
+    for frame in frames {
+        match frame.downcast_ref::<Error>() {
+            Some(Error::TemplateFailures | Error::TestFailures) => {
+                collect_diagnostics(frame.sources(), &mut Vec::new(), diagnostics)
+            }
+            Some(error) => diagnostics.extend(error_diagnostics(error, frame, attachments)),
+            None => {
+                let depth = attachments.len();
+                if matches!(frame.kind(), FrameKind::Attachment(_)) {
+                    attachments.push(frame);
+                }
+                collect_diagnostics(frame.sources(), attachments, diagnostics);
+                attachments.truncate(depth);
+            }
+        }
+    }
+}
+
+fn error_diagnostics(error: &Error, frame: &Frame, attachments: &[&Frame]) -> Vec<Diagnostic> {
+    if let Some(diagnostic) = attachments
+        .iter()
+        .find_map(|a| a.downcast_ref::<Diagnostic>())
+    {
+        return vec![diagnostic.clone()];
+    }
+
+    if let Some(StaleFiles(paths)) = attachments.iter().find_map(|a| a.downcast_ref()) {
+        return paths
+            .iter()
+            .map(|path| Diagnostic::new(error, error.to_string()).with_path(path))
+            .collect();
+    }
+
+    // The lowest context in the report is usually the most specific description of the problem.
+    let mut cause = None;
+    let mut source = frame.sources().first();
+    while let Some(f) = source {
+        if let FrameKind::Context(context) = f.kind() {
+            cause = Some(context.to_string());
+        }
+        source = f.sources().first();
+    }
+
+    let message = match cause {
+        Some(cause) => format!("{error}: {cause}"),
+        None => error.to_string(),
+    };
+    let mut diagnostic = Diagnostic::new(error, message);
+
+    // Attachments are stored newest first, so reverse them to get the order they were added.
+    let printable = attachments.iter().rev().filter_map(|a| match a.kind() {
+        FrameKind::Attachment(AttachmentKind::Printable(p)) => Some(p.to_string()),
+        _ => None,
+    });
+    for text in printable {
+        if text.is_empty() {
+            continue;
+        }
+
+        if diagnostic.location.is_none() && Path::new(&text).exists() {
+            diagnostic = diagnostic.with_path(Path::new(&text));
+        } else {
+            diagnostic.notes.push(text);
+        }
+    }
+
+    vec![diagnostic]
+}
+
+/// Build a SARIF 2.1.0 log containing the diagnostics.
+pub fn sarif_log(diagnostics: &[Diagnostic]) -> serde_json::Value {
+    let rules = diagnostics
+        .iter()
+        .map(|d| d.code)
+        .unique()
+        .map(|code| serde_json::json!({ "id": code }))
+        .collect::<Vec<_>>();
+
+    let results = diagnostics
+        .iter()
+        .map(|d| {
+            let mut message = d.message.clone();
+            for note in &d.notes {
+                message.push_str(&format!("\nnote: {note}"));
+            }
+
+            let mut result = serde_json::json!({
+                "ruleId": d.code,
+                "level": d.severity.to_string(),
+                "message": { "text": message },
+            });
+
+            if let Some(location) = &d.location {
+                let path = location.path.to_string_lossy().replace('\\', "/");
+                let uri = match (location.path.is_absolute(), path.starts_with('/')) {
+                    (true, true) => format!("file://{path}"),
+                    (true, false) => format!("file:///{path}"),
+                    (false, _) => path,
+                };
+                let mut physical = serde_json::json!({
+                    "artifactLocation": { "uri": uri },
+                });
+                if let Some(span) = location.span {
+                    physical["region"] = serde_json::json!({
+                        "startLine": span.line,
+                        "startColumn": span.column,
+                        "endColumn": span.column + span.length.max(1),
+                    });
+                }
+                result["locations"] = serde_json::json!([{ "physicalLocation": physical }]);
+            }
+
+            result
+        })
+        .collect::<Vec<_>>();
+
+    serde_json::json!({
+        "$schema": "https://json.schemastore.org/sarif-2.1.0.json",
+        "version": "2.1.0",
+        "runs": [{
+            "tool": {
+                "driver": {
+                    "name": "sqlweld",
+                    "version": env!("CARGO_PKG_VERSION"),
+                    "informationUri": "https://github.com/dimfeld/sqlweld",
+                    "rules": rules,
+                },
+            },
+            "results": results,
+        }],
+    })
+}
+
+/// Print a progress message. When the diagnostics are machine-readable this goes to stderr, so
+/// that stdout only holds the diagnostics.
+pub(crate) fn print_status(format: MessageFormat, message: impl std::fmt::Display) {
+    if format == MessageFormat::Human {
+        println!("{message}");
+    } else {
+        eprintln!("{message}");
+    }
+}
+
+/// Print diagnostics in the given format.
+pub fn print_diagnostics(format: MessageFormat, diagnostics: &[Diagnostic]) {
+    match format {
+        MessageFormat::Human => {
+            for diagnostic in diagnostics {
+                eprintln!("{}: {diagnostic}", diagnostic.severity);
+            }
+        }
+        MessageFormat::Json => {
+            for diagnostic in diagnostics {
+                println!(
+                    "{}",
+                    serde_json::to_string(diagnostic).expect("diagnostics can be serialized")
+                );
+            }
+        }
+        MessageFormat::Sarif => {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&sarif_log(diagnostics))
+                    .expect("diagnostics can be serialized")
+            );
+        }
+    }
+}
@@ -0,0 +1,879 @@
+//! A built-in SQL formatter, so that formatting doesn't depend on an external command.
+//!
+//! Each clause of a query starts on its own line. A clause's contents stay on the same line as its
+//! keyword when they fit within the line width, and otherwise are split one item per line. Items
+//! are split at commas, or before `AND` and `OR` in conditions. Parenthesized groups are kept
+//! inline when they fit, and subqueries are always placed on their own lines.
+//!
+//! Line breaks are only added or removed where whitespace is not significant, and the text of each
+//! token is unchanged except for the case of keywords.
+
+/// Formatting with the built-in formatter, as an alternative to an external command.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, serde::Serialize, serde::Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// Format the SQL with the formatter built into sqlweld.
+    Builtin,
+}
+
+/// How the built-in formatter writes SQL keywords.
+#[derive(
+    Debug,
+    Default,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    clap::ValueEnum,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum KeywordCase {
+    #[default]
+    Upper,
+    Lower,
+    /// Leave keywords as they were written in the template.
+    Preserve,
+}
+
+/// Settings for the built-in formatter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct FormatOptions {
+    /// The number of spaces for each level of indentation.
+    pub indent: usize,
+    pub keyword_case: KeywordCase,
+    /// Try to keep lines no longer than this.
+    pub line_width: usize,
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        FormatOptions {
+            indent: 2,
+            keyword_case: KeywordCase::Upper,
+            line_width: 80,
+        }
+    }
+}
+
+/// Words which have their case changed according to [KeywordCase].
+const KEYWORDS: &[&str] = &[
+    "ALL",
+    "AND",
+    "ANY",
+    "AS",
+    "ASC",
+    "BETWEEN",
+    "BY",
+    "CASE",
+    "CAST",
+    "CONFLICT",
+    "CROSS",
+    "DEFAULT",
+    "DELETE",
+    "DESC",
+    "DISTINCT",
+    "DO",
+    "ELSE",
+    "END",
+    "EXCEPT",
+    "EXISTS",
+    "FALSE",
+    "FETCH",
+    "FILTER",
+    "FOR",
+    "FROM",
+    "FULL",
+    "GROUP",
+    "HAVING",
+    "ILIKE",
+    "IN",
+    "INNER",
+    "INSERT",
+    "INTERSECT",
+    "INTERVAL",
+    "INTO",
+    "IS",
+    "JOIN",
+    "LATERAL",
+    "LEFT",
+    "LIKE",
+    "LIMIT",
+    "NOT",
+    "NOTHING",
+    "NULL",
+    "NULLS",
+    "OFFSET",
+    "ON",
+    "OR",
+    "ORDER",
+    "OUTER",
+    "OVER",
+    "PARTITION",
+    "RECURSIVE",
+    "RETURNING",
+    "RIGHT",
+    "SELECT",
+    "SET",
+    "SHARE",
+    "SOME",
+    "THEN",
+    "TRUE",
+    "UNION",
+    "UPDATE",
+    "USING",
+    "VALUES",
+    "WHEN",
+    "WHERE",
+    "WINDOW",
+    "WITH",
+];
+
+/// Keywords that start a new clause. Longer sequences come before the shorter ones that they
+/// start with.
+const CLAUSES: &[(&[&str], ClauseKind)] = &[
+    (&["WITH", "RECURSIVE"], ClauseKind::List),
+    (&["WITH"], ClauseKind::List),
+    (&["SELECT"], ClauseKind::List),
+    (&["INSERT", "INTO"], ClauseKind::List),
+    (&["UPDATE"], ClauseKind::List),
+    (&["DELETE", "FROM"], ClauseKind::List),
+    (&["FROM"], ClauseKind::List),
+    (&["INNER", "JOIN"], ClauseKind::Join),
+    (&["LEFT", "OUTER", "JOIN"], ClauseKind::Join),
+    (&["LEFT", "JOIN"], ClauseKind::Join),
+    (&["RIGHT", "OUTER", "JOIN"], ClauseKind::Join),
+    (&["RIGHT", "JOIN"], ClauseKind::Join),
+    (&["FULL", "OUTER", "JOIN"], ClauseKind::Join),
+    (&["FULL", "JOIN"], ClauseKind::Join),
+    (&["CROSS", "JOIN"], ClauseKind::Join),
+    (&["JOIN"], ClauseKind::Join),
+    (&["WHERE"], ClauseKind::Condition),
+    (&["GROUP", "BY"], ClauseKind::List),
+    (&["HAVING"], ClauseKind::Condition),
+    (&["WINDOW"], ClauseKind::List),
+    (&["ORDER", "BY"], ClauseKind::List),
+    (&["LIMIT"], ClauseKind::List),
+    (&["OFFSET"], ClauseKind::List),
+    (&["FETCH"], ClauseKind::List),
+    (&["FOR", "UPDATE"], ClauseKind::List),
+    (&["FOR", "SHARE"], ClauseKind::List),
+    (&["UNION", "ALL"], ClauseKind::List),
+    (&["UNION"], ClauseKind::List),
+    (&["INTERSECT"], ClauseKind::List),
+    (&["EXCEPT"], ClauseKind::List),
+    (&["VALUES"], ClauseKind::List),
+    (&["SET"], ClauseKind::List),
+    (&["ON", "CONFLICT"], ClauseKind::List),
+    (&["DO", "UPDATE", "SET"], ClauseKind::List),
+    (&["DO", "NOTHING"], ClauseKind::List),
+    (&["RETURNING"], ClauseKind::List),
+];
+
+/// Keywords which start a query when they are the first word inside parentheses.
+const QUERY_STARTS: &[&str] = &["DELETE", "INSERT", "SELECT", "UPDATE", "VALUES", "WITH"];
+
+fn is_word(token: &Token, word: &str) -> bool {
+    token.kind == Kind::Word && token.text.eq_ignore_ascii_case(word)
+}
+
+fn is_keyword(text: &str) -> bool {
+    KEYWORDS.iter().any(|k| k.eq_ignore_ascii_case(text))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    /// Identifiers, keywords, numbers, and placeholders.
+    Word,
+    /// String literals and quoted identifiers.
+    Quoted,
+    LineComment,
+    BlockComment,
+    Open,
+    Close,
+    Comma,
+    Semicolon,
+    Dot,
+    Operator,
+    /// Anything else, which keeps its original spacing.
+    Other,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Token<'a> {
+    kind: Kind,
+    text: &'a str,
+    /// The token was preceded by whitespace.
+    space_before: bool,
+    /// The token was preceded by a line break.
+    newline_before: bool,
+    /// The token was preceded by an empty line.
+    blank_line_before: bool,
+    /// The token is a prefix operator such as the `-` in `-1`.
+    unary: bool,
+    /// The token is the first in its statement or parentheses, not counting comments.
+    first: bool,
+}
+
+impl<'a> Token<'a> {
+    fn is_keyword(&self) -> bool {
+        // `WITH` is only a keyword at the start of a query, as opposed to `WITH TIME ZONE`.
+        self.kind == Kind::Word
+            && is_keyword(self.text)
+            && (self.first || !self.text.eq_ignore_ascii_case("WITH"))
+    }
+}
+
+fn tokenize(sql: &str) -> Vec<Token<'_>> {
+    let mut tokens: Vec<Token> = Vec::new();
+    let mut rest = sql;
+    let mut whitespace = "";
+
+    while !rest.is_empty() {
+        let c = rest.chars().next().unwrap();
+        if c.is_whitespace() {
+            let len = rest
+                .find(|c: char| !c.is_whitespace())
+                .unwrap_or(rest.len());
+            whitespace = &rest[..len];
+            rest = &rest[len..];
+            continue;
+        }
+
+        let (kind, len) = next_token(rest);
+        let prev = tokens
+            .last()
+            .filter(|t| t.kind != Kind::LineComment && t.kind != Kind::BlockComment);
+        let unary = kind == Kind::Operator
+            && matches!(&rest[..len], "-" | "+" | "~")
+            && prev
+                .map(|p| {
+                    matches!(p.kind, Kind::Operator | Kind::Open | Kind::Comma) || p.is_keyword()
+                })
+                .unwrap_or(true);
+
+        let newlines = whitespace.matches('\n').count();
+        tokens.push(Token {
+            kind,
+            text: &rest[..len],
+            space_before: !whitespace.is_empty(),
+            newline_before: newlines > 0,
+            blank_line_before: newlines > 1,
+            unary,
+            first: prev
+                .map(|p| matches!(p.kind, Kind::Open | Kind::Semicolon))
+                .unwrap_or(true),
+        });
+        rest = &rest[len..];
+        whitespace = "";
+    }
+
+    tokens
+}
+
+/// The kind and length of the token at the start of `s`.
+fn next_token(s: &str) -> (Kind, usize) {
+    let bytes = s.as_bytes();
+    let c = s.chars().next().unwrap();
+    let next = bytes.get(1).copied().unwrap_or(0);
+
+    match c {
+        '-' if next == b'-' => (Kind::LineComment, s.find('\n').unwrap_or(s.len())),
+        '/' if next == b'*' => (
+            Kind::BlockComment,
+            s[2..].find("*/").map(|i| i + 4).unwrap_or(s.len()),
+        ),
+        '\'' => (Kind::Quoted, quoted_len(s, '\'', false)),
+        '"' | '`' => (Kind::Quoted, quoted_len(s, c, false)),
+        '(' => (Kind::Open, 1),
+        ')' => (Kind::Close, 1),
+        ',' => (Kind::Comma, 1),
+        ';' => (Kind::Semicolon, 1),
+        '.' if !next.is_ascii_digit() => (Kind::Dot, 1),
+        '$' if next == b'[' => match s.find(']') {
+            Some(end) => (Kind::Word, end + 1),
+            None => (Kind::Other, 1),
+        },
+        '$' => match dollar_quoted_len(s) {
+            Some(len) => (Kind::Quoted, len),
+            None => (Kind::Word, word_len(&s[1..]) + 1),
+        },
+        '%' if next == b'(' => match s.find(")s") {
+            Some(end) if s[2..end].chars().all(|c| c.is_alphanumeric() || c == '_') => {
+                (Kind::Word, end + 2)
+            }
+            _ => (Kind::Operator, operator_len(s)),
+        },
+        ':' if next == b':' => (Kind::Operator, 2),
+        ':' if (next as char).is_ascii_alphabetic() => (Kind::Word, word_len(&s[1..]) + 1),
+        '?' => (Kind::Word, 1),
+        c if c.is_alphanumeric() || c == '_' || c == '.' => {
+            let len = word_len(s);
+            // String prefixes such as E'...' are part of the string.
+            if s[len..].starts_with('\'') {
+                let escapes = s[..len].eq_ignore_ascii_case("e");
+                (Kind::Quoted, len + quoted_len(&s[len..], '\'', escapes))
+            } else {
+                (Kind::Word, len)
+            }
+        }
+        '+' | '-' | '*' | '/' | '<' | '>' | '=' | '~' | '!' | '@' | '#' | '%' | '^' | '&' | '|' => {
+            (Kind::Operator, operator_len(s))
+        }
+        c => (Kind::Other, c.len_utf8()),
+    }
+}
+
+/// The length of the identifier, keyword, or number at the start of `s`.
+fn word_len(s: &str) -> usize {
+    let number = s.starts_with(|c: char| c.is_ascii_digit() || c == '.');
+    s.find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '$' || (number && c == '.')))
+        .unwrap_or(s.len())
+}
+
+fn operator_len(s: &str) -> usize {
+    let mut len = s
+        .find(|c: char| !"+-*/<>=~!@#%^&|".contains(c))
+        .unwrap_or(s.len());
+    // A comment ends the operator.
+    for start in ["--", "/*"] {
+        if let Some(i) = s[..len].find(start) {
+            len = len.min(i.max(1));
+        }
+    }
+
+    // As in PostgreSQL, an operator only ends in + or - if it contains one of these characters.
+    // This keeps the `-` of `=-1` separate.
+    let op = &s[..len];
+    if len > 1 && !op.contains(|c: char| "~!@#%^&|".contains(c)) {
+        len = op.trim_end_matches(['+', '-']).len().max(1);
+    }
+    len
+}
+
+/// The length of the quoted text at the start of `s`, including the quotes.
+fn quoted_len(s: &str, quote: char, escapes: bool) -> usize {
+    let mut chars = s.char_indices().skip(1).peekable();
+    while let Some((i, c)) = chars.next() {
+        if escapes && c == '\\' {
+            chars.next();
+        } else if c == quote {
+            // A doubled quote is an escaped quote inside the string.
+            if chars.peek().map(|(_, c)| *c == quote).unwrap_or(false) {
+                chars.next();
+                continue;
+            }
+            return i + 1;
+        }
+    }
+
+    s.len()
+}
+
+/// The length of a PostgreSQL dollar-quoted string such as `$$text$$` or `$tag$text$tag$`.
+fn dollar_quoted_len(s: &str) -> Option<usize> {
+    let (end, c) = s
+        .char_indices()
+        .skip(1)
+        .find(|(_, c)| !(c.is_alphanumeric() || *c == '_'))?;
+    if c != '$' || s[1..end].starts_with(|c: char| c.is_ascii_digit()) {
+        return None;
+    }
+
+    let tag_len = end + 1;
+    let tag = &s[..tag_len];
+
+    let end = s[tag_len..].find(tag)?;
+    Some(tag_len + end + tag_len)
+}
+
+#[derive(Debug)]
+enum Node<'a> {
+    Token(Token<'a>),
+    Group {
+        open: Token<'a>,
+        inner: Vec<Node<'a>>,
+        close: Token<'a>,
+    },
+}
+
+impl<'a> Node<'a> {
+    fn first(&self) -> &Token<'a> {
+        match self {
+            Node::Token(t) => t,
+            Node::Group { open, .. } => open,
+        }
+    }
+
+    fn token(&self) -> Option<&Token<'a>> {
+        match self {
+            Node::Token(t) => Some(t),
+            Node::Group { .. } => None,
+        }
+    }
+}
+
+/// Nest the tokens inside parentheses, or return None if the parentheses are unbalanced.
+fn parse(tokens: Vec<Token>) -> Option<Vec<Node>> {
+    let mut stack: Vec<(Token, Vec<Node>)> = Vec::new();
+    let mut nodes = Vec::new();
+    for token in tokens {
+        match token.kind {
+            Kind::Open => stack.push((token, std::mem::take(&mut nodes))),
+            Kind::Close => {
+                let (open, outer) = stack.pop()?;
+                let inner = std::mem::replace(&mut nodes, outer);
+                nodes.push(Node::Group {
+                    open,
+                    inner,
+                    close: token,
+                });
+            }
+            _ => nodes.push(Node::Token(token)),
+        }
+    }
+
+    stack.is_empty().then_some(nodes)
+}
+
+/// Whether the nodes inside a group are a subquery.
+fn is_query(nodes: &[Node]) -> bool {
+    nodes
+        .iter()
+        .find(|n| !matches!(n.first().kind, Kind::LineComment | Kind::BlockComment))
+        .map(|n| {
+            QUERY_STARTS
+                .iter()
+                .any(|word| n.token().map(|t| is_word(t, word)).unwrap_or(false))
+        })
+        .unwrap_or(false)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ClauseKind {
+    /// Items separated by commas.
+    List,
+    /// Conditions joined with `AND` and `OR`.
+    Condition,
+    /// A table followed by `ON` or `USING` and conditions.
+    Join,
+}
+
+struct Clause<'n, 'a> {
+    /// Comments on the lines before the clause.
+    leading: Vec<&'n Token<'a>>,
+    keyword: &'n [Node<'a>],
+    kind: ClauseKind,
+    body: &'n [Node<'a>],
+}
+
+/// If a clause starts at `nodes[i]`, return the number of keywords and its kind.
+fn match_clause(nodes: &[Node], i: usize) -> Option<(usize, ClauseKind)> {
+    let prev = i.checked_sub(1).and_then(|i| nodes[i].token());
+    // Avoid splitting `IS DISTINCT FROM`.
+    if prev.map(|p| is_word(p, "DISTINCT")).unwrap_or(false) {
+        return None;
+    }
+
+    CLAUSES.iter().find_map(|(words, kind)| {
+        let matches = words.iter().enumerate().all(|(j, word)| {
+            nodes
+                .get(i + j)
+                .and_then(|n| n.token())
+                .map(|t| is_word(t, word))
+                .unwrap_or(false)
+        });
+        // `WITH` only starts a clause at the start of a query, as opposed to `WITH TIME ZONE`.
+        let valid = words[0] != "WITH" || nodes[..i].iter().all(|n| is_comment(n));
+        (matches && valid).then_some((words.len(), *kind))
+    })
+}
+
+fn is_comment(node: &Node) -> bool {
+    matches!(
+        node.token().map(|t| t.kind),
+        Some(Kind::LineComment | Kind::BlockComment)
+    )
+}
+
+fn split_clauses<'n, 'a>(nodes: &'n [Node<'a>]) -> Vec<Clause<'n, 'a>> {
+    let mut clauses = Vec::new();
+    let mut current = Clause {
+        leading: Vec::new(),
+        keyword: &nodes[..0],
+        kind: ClauseKind::List,
+        body: &nodes[..0],
+    };
+    let mut body_start = 0;
+
+    let mut i = 0;
+    while i < nodes.len() {
+        let Some((len, kind)) = match_clause(nodes, i) else {
+            i += 1;
+            continue;
+        };
+
+        // Comments on their own lines just before the keyword go with the new clause.
+        let mut body_end = i;
+        while body_end > body_start
+            && is_comment(&nodes[body_end - 1])
+            && nodes[body_end - 1].first().newline_before
+        {
+            body_end -= 1;
+        }
+
+        current.body = &nodes[body_start..body_end];
+        if !current.keyword.is_empty() || !current.body.is_empty() || !current.leading.is_empty() {
+            clauses.push(current);
+        }
+
+        current = Clause {
+            leading: nodes[body_end..i]
+                .iter()
+                .filter_map(|n| n.token())
+                .collect(),
+            keyword: &nodes[i..i + len],
+            kind,
+            body: &nodes[..0],
+        };
+        i += len;
+        body_start = i;
+    }
+
+    current.body = &nodes[body_start..];
+    if !current.keyword.is_empty() || !current.body.is_empty() || !current.leading.is_empty() {
+        clauses.push(current);
+    }
+    clauses
+}
+
+/// Split a clause's body into the items that go on separate lines.
+fn split_items<'n, 'a>(body: &'n [Node<'a>], kind: ClauseKind) -> Vec<&'n [Node<'a>]> {
+    let mut items = Vec::new();
+    let mut start = 0;
+    let mut case_depth = 0usize;
+    let mut in_between = false;
+
+    let mut i = 0;
+    while i < body.len() {
+        let token = body[i].token();
+        let is = |word| token.map(|t| is_word(t, word)).unwrap_or(false);
+
+        if is("CASE") {
+            case_depth += 1;
+        } else if is("END") {
+            case_depth = case_depth.saturating_sub(1);
+        }
+
+        match kind {
+            ClauseKind::List => {
+                if token.map(|t| t.kind == Kind::Comma).unwrap_or(false) {
+                    // Keep a comment on the same line with the item before it.
+                    let mut end = i + 1;
+                    while body
+                        .get(end)
+                        .map(|n| is_comment(n) && !n.first().newline_before)
+                        .unwrap_or(false)
+                    {
+                        end += 1;
+                    }
+                    items.push(&body[start..end]);
+                    start = end;
+                    i = end;
+                    continue;
+                }
+            }
+            ClauseKind::Condition | ClauseKind::Join => {
+                if is("BETWEEN") {
+                    in_between = true;
+                } else if is("AND") && in_between {
+                    in_between = false;
+                } else if case_depth == 0
+                    && i > start
+                    && (is("AND")
+                        || is("OR")
+                        || (kind == ClauseKind::Join && (is("ON") || is("USING"))))
+                {
+                    items.push(&body[start..i]);
+                    start = i;
+                }
+            }
+        }
+
+        i += 1;
+    }
+
+    if start < body.len() {
+        items.push(&body[start..]);
+    }
+    items
+}
+
+struct Writer<'o, 'a> {
+    options: &'o FormatOptions,
+    output: String,
+    indent: usize,
+    /// The number of line breaks to write before the next token.
+    pending_newlines: usize,
+    prev: Option<Token<'a>>,
+}
+
+impl<'o, 'a> Writer<'o, 'a> {
+    fn new(options: &'o FormatOptions) -> Self {
+        Writer {
+            options,
+            output: String::new(),
+            indent: 0,
+            pending_newlines: 0,
+            prev: None,
+        }
+    }
+
+    fn newline(&mut self) {
+        self.pending_newlines = self.pending_newlines.max(1);
+    }
+
+    fn blank_line(&mut self) {
+        self.pending_newlines = 2;
+    }
+
+    fn column(&self) -> usize {
+        if self.pending_newlines > 0 || self.output.is_empty() {
+            self.indent * self.options.indent
+        } else {
+            let line_start = self.output.rfind('\n').map(|i| i + 1).unwrap_or(0);
+            self.output[line_start..].chars().count()
+        }
+    }
+
+    /// Whether text of length `len`, starting with `first`, fits on the current line.
+    fn fits(&self, first: &Token, len: usize) -> bool {
+        let space = self.pending_newlines == 0 && self.needs_space(first);
+        self.column() + usize::from(space) + len <= self.options.line_width
+    }
+
+    fn needs_space(&self, next: &Token) -> bool {
+        let Some(prev) = self.prev.as_ref() else {
+            return false;
+        };
+
+        match (prev.kind, next.kind) {
+            (_, Kind::Comma | Kind::Semicolon | Kind::Close | Kind::Dot) => false,
+            (Kind::Open | Kind::Dot, _) => false,
+            (Kind::Comma, _) => true,
+            _ if prev.text == "::" || next.text == "::" => false,
+            (Kind::Operator, _) if prev.unary => false,
+            (Kind::Operator, _) => true,
+            (_, Kind::Operator) if !next.unary => true,
+            _ => next.space_before,
+        }
+    }
+
+    fn write_token(&mut self, token: &Token<'a>) {
+        if let Some(prev) = self.prev.as_ref() {
+            if prev.kind == Kind::LineComment {
+                self.pending_newlines = self.pending_newlines.max(1);
+                if token.blank_line_before {
+                    self.pending_newlines = 2;
+                }
+            } else if token.kind == Kind::LineComment && token.newline_before {
+                self.newline();
+            }
+        }
+
+        if self.output.is_empty() {
+            self.pending_newlines = 0;
+        }
+
+        if self.pending_newlines > 0 {
+            let trimmed = self.output.trim_end_matches(' ').len();
+            self.output.truncate(trimmed);
+            for _ in 0..self.pending_newlines {
+                self.output.push('\n');
+            }
+            let indent = self.indent * self.options.indent;
+            self.output.push_str(&" ".repeat(indent));
+            self.pending_newlines = 0;
+        } else if self.needs_space(token) {
+            self.output.push(' ');
+        }
+
+        let after_dot = self.prev.map(|p| p.kind == Kind::Dot).unwrap_or(false);
+        if token.is_keyword() && !after_dot {
+            match self.options.keyword_case {
+                KeywordCase::Upper => self.output.push_str(&token.text.to_uppercase()),
+                KeywordCase::Lower => self.output.push_str(&token.text.to_lowercase()),
+                KeywordCase::Preserve => self.output.push_str(token.text),
+            }
+        } else {
+            self.output.push_str(token.text);
+        }
+
+        self.prev = Some(*token);
+    }
+
+    /// Render `nodes` on a single line, or return None if they must be split.
+    fn inline(&self, nodes: &[Node<'a>]) -> Option<String> {
+        let mut writer = Writer::new(self.options);
+        writer.write_inline(nodes)?;
+        Some(writer.output)
+    }
+
+    fn write_inline(&mut self, nodes: &[Node<'a>]) -> Option<()> {
+        for node in nodes {
+            match node {
+                Node::Token(t) if t.kind == Kind::LineComment => return None,
+                Node::Token(t) => self.write_token(t),
+                Node::Group { inner, .. } if is_query(inner) => return None,
+                Node::Group { open, inner, close } => {
+                    self.write_token(open);
+                    self.write_inline(inner)?;
+                    self.write_token(close);
+                }
+            }
+        }
+
+        Some(())
+    }
+
+    /// Write `nodes` on the current line, if they fit.
+    fn try_inline(&mut self, nodes: &[Node<'a>]) -> bool {
+        let Some(first) = nodes.first() else {
+            return true;
+        };
+
+        match self.inline(nodes) {
+            Some(text) if self.fits(first.first(), text.chars().count()) => {
+                self.write_inline(nodes);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Write `nodes`, splitting the groups inside them onto separate lines when needed.
+    fn write_nodes(&mut self, nodes: &[Node<'a>]) {
+        for node in nodes {
+            match node {
+                Node::Token(t) => self.write_token(t),
+                Node::Group { open, inner, close } => {
+                    self.write_token(open);
+                    // Leave room for the closing parenthesis.
+                    let fits = inner.is_empty()
+                        || (!is_query(inner)
+                            && self.inline(inner).is_some_and(|text| {
+                                self.fits(inner[0].first(), text.chars().count() + 1)
+                            }));
+
+                    if fits {
+                        self.write_inline(inner);
+                    } else {
+                        self.indent += 1;
+                        self.write_block(inner);
+                        self.indent -= 1;
+                        self.newline();
+                    }
+                    self.write_token(close);
+                }
+            }
+        }
+    }
+
+    /// Write a sequence of statements, with each clause on its own line.
+    fn write_block(&mut self, nodes: &[Node<'a>]) {
+        let statements = nodes.split_inclusive(|n| {
+            n.token()
+                .map(|t| t.kind == Kind::Semicolon)
+                .unwrap_or(false)
+        });
+
+        for (i, statement) in statements.enumerate() {
+            if i > 0 {
+                self.blank_line();
+            }
+
+            let (statement, semicolon) = match statement.split_last() {
+                Some((last, rest)) if last.first().kind == Kind::Semicolon => (rest, Some(last)),
+                _ => (statement, None),
+            };
+
+            for clause in split_clauses(statement) {
+                self.write_clause(&clause);
+            }
+
+            if let Some(semicolon) = semicolon {
+                self.write_nodes(std::slice::from_ref(semicolon));
+            }
+        }
+    }
+
+    fn write_clause(&mut self, clause: &Clause<'_, 'a>) {
+        for comment in &clause.leading {
+            self.newline();
+            self.write_token(comment);
+        }
+
+        self.newline();
+        let items = split_items(clause.body, clause.kind);
+
+        if clause.keyword.is_empty() {
+            // Text before the first clause, or the contents of a group that didn't fit.
+            for item in items {
+                self.newline();
+                self.write_nodes(item);
+            }
+            return;
+        }
+
+        self.write_nodes(clause.keyword);
+
+        // A comment at the end of the clause's last line doesn't stop it from staying on one line.
+        let mut inline_end = clause.body.len();
+        while inline_end > 0
+            && is_comment(&clause.body[inline_end - 1])
+            && !clause.body[inline_end - 1].first().newline_before
+        {
+            inline_end -= 1;
+        }
+        if clause.body.is_empty() || self.try_inline(&clause.body[..inline_end]) {
+            self.write_nodes(&clause.body[inline_end..]);
+            return;
+        }
+
+        let mut items = items.into_iter();
+        if clause.kind == ClauseKind::Join {
+            // The table stays with the JOIN keyword.
+            if let Some(table) = items.next() {
+                self.write_nodes(table);
+            }
+        }
+
+        self.indent += 1;
+        for item in items {
+            self.newline();
+            self.write_nodes(item);
+        }
+        self.indent -= 1;
+    }
+}
+
+/// Format `sql` with the built-in formatter.
+pub(crate) fn format_sql(sql: &str, options: &FormatOptions) -> String {
+    let tokens = tokenize(sql);
+    if tokens.is_empty() {
+        return sql.to_string();
+    }
+
+    // Without balanced parentheses the structure of the query is unclear, so leave it alone.
+    let Some(nodes) = parse(tokens) else {
+        return sql.to_string();
+    };
+
+    let mut writer = Writer::new(options);
+    writer.write_block(&nodes);
+    writer.output.push('\n');
+    writer.output
+}
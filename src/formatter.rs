@@ -0,0 +1,262 @@
+use std::{
+    convert::Infallible,
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    str::FromStr,
+};
+
+use error_stack::{Report, ResultExt};
+use wait_timeout::ChildExt;
+
+use crate::{Error, Options, SqlDialect};
+
+/// How the formatter command receives the SQL.
+#[derive(
+    Debug,
+    Default,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    clap::ValueEnum,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum FormatterMode {
+    /// Run the formatter once for each file, passing the SQL on stdin and reading the formatted SQL
+    /// from stdout.
+    #[default]
+    Stdin,
+    /// Write all the files to a temporary directory, and run the formatter once with all of their
+    /// paths, for formatters that format files in place.
+    Batch,
+}
+
+/// An external command that formats the generated SQL.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
+#[serde(untagged)]
+pub(crate) enum FormatterCommand {
+    /// A command line, which is split into arguments the way a shell would.
+    Shell(String),
+    /// The program followed by its arguments.
+    Args(Vec<String>),
+}
+
+impl FromStr for FormatterCommand {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(FormatterCommand::Shell(s.to_string()))
+    }
+}
+
+impl FormatterCommand {
+    /// The program and its arguments, with `{dialect}` filled in.
+    fn args(&self, dialect: Option<SqlDialect>) -> Result<Vec<String>, Report<Error>> {
+        let args = match self {
+            FormatterCommand::Shell(command) => shell_words::split(command)
+                .change_context(Error::Formatter)
+                .attach_printable_lazy(|| {
+                    format!("Could not parse formatter command `{command}`")
+                })?,
+            FormatterCommand::Args(args) => args.clone(),
+        };
+
+        if args.is_empty() {
+            return Err(Error::Formatter).attach_printable("The formatter command is empty");
+        }
+
+        args.into_iter()
+            .map(|arg| {
+                if !arg.contains("{dialect}") {
+                    return Ok(arg);
+                }
+
+                let Some(dialect) = dialect else {
+                    return Err(Error::Formatter).attach_printable(
+                        "The formatter command uses {dialect}, but no dialect is set",
+                    );
+                };
+                Ok(arg.replace("{dialect}", dialect.name()))
+            })
+            .collect()
+    }
+}
+
+/// Pass `output` through the formatter command and return the formatted result. `path` is the
+/// file that the output will be written to.
+pub(crate) fn run_formatter(
+    options: &Options,
+    formatter: &FormatterCommand,
+    path: &Path,
+    output: String,
+) -> Result<String, Report<Error>> {
+    let path = path.display().to_string();
+    let args = formatter
+        .args(options.dialect.or(options.validate))?
+        .into_iter()
+        .map(|arg| arg.replace("{path}", &path))
+        .collect::<Vec<_>>();
+
+    let stdout = run(options, &args, Some(output))?;
+    String::from_utf8(stdout).change_context(Error::Formatter)
+}
+
+/// Format each of `files`, given as pairs of output path and SQL, with a single run of the
+/// formatter. The SQL is written to files in a temporary directory, the formatter is run with all
+/// of their paths, and then the formatted files are read back.
+pub(crate) fn run_batch_formatter(
+    options: &Options,
+    formatter: &FormatterCommand,
+    files: &[(&Path, &str)],
+) -> Result<Vec<String>, Report<Error>> {
+    if files.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let args = formatter.args(options.dialect.or(options.validate))?;
+    if args.iter().any(|arg| arg.contains("{path}")) {
+        return Err(Error::Formatter)
+            .attach_printable("{path} can not be used in batch mode. Use {paths} instead.");
+    }
+
+    // Each file gets its own directory so that the formatter sees the same file names as the
+    // output files, even when two of them have the same name.
+    let dir = tempfile::tempdir().change_context(Error::Formatter)?;
+    let paths = files
+        .iter()
+        .enumerate()
+        .map(|(i, (output_path, sql))| {
+            let file_dir = dir.path().join(i.to_string());
+            std::fs::create_dir(&file_dir)?;
+            let path = file_dir.join(output_path.file_name().unwrap_or("query.sql".as_ref()));
+            std::fs::write(&path, sql)?;
+            Ok(path)
+        })
+        .collect::<Result<Vec<PathBuf>, std::io::Error>>()
+        .change_context(Error::Formatter)?;
+
+    let path_args = paths.iter().map(|path| path.display().to_string());
+    let args = if args.iter().any(|arg| arg == "{paths}") {
+        args.into_iter()
+            .flat_map(|arg| {
+                if arg == "{paths}" {
+                    path_args.clone().collect::<Vec<_>>()
+                } else {
+                    vec![arg]
+                }
+            })
+            .collect::<Vec<_>>()
+    } else {
+        args.into_iter().chain(path_args).collect()
+    };
+
+    run(options, &args, None)?;
+
+    paths
+        .iter()
+        .map(|path| {
+            std::fs::read_to_string(path)
+                .change_context(Error::Formatter)
+                .attach_printable_lazy(|| format!("Could not read {} back", path.display()))
+        })
+        .collect()
+}
+
+/// Run the formatter process, passing `input` on stdin if it is given, and return its stdout.
+fn run(
+    options: &Options,
+    args: &[String],
+    input: Option<String>,
+) -> Result<Vec<u8>, Report<Error>> {
+    let mut command = Command::new(&args[0]);
+    command
+        .args(&args[1..])
+        .stdin(if input.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+
+    if !options.formatter_env.is_empty() {
+        command.env_clear();
+        let names = std::iter::once("PATH").chain(options.formatter_env.iter().map(String::as_str));
+        for name in names {
+            if let Some(value) = std::env::var_os(name) {
+                command.env(name, value);
+            }
+        }
+    }
+
+    let mut format_process = command
+        .spawn()
+        .change_context(Error::Formatter)
+        .attach_printable_lazy(|| format!("Could not run `{}`", args[0]))?;
+
+    let writer_thread = match input {
+        Some(input) => {
+            let mut stdin = format_process.stdin.take().ok_or(Error::Formatter)?;
+            Some(std::thread::spawn(move || {
+                stdin
+                    .write_all(input.as_bytes())
+                    .change_context(Error::Formatter)
+            }))
+        }
+        None => None,
+    };
+
+    let stdout = read_in_thread(format_process.stdout.take().ok_or(Error::Formatter)?);
+    let stderr = read_in_thread(format_process.stderr.take().ok_or(Error::Formatter)?);
+
+    let status = match options.formatter_timeout {
+        Some(timeout) => {
+            let status = format_process
+                .wait_timeout(timeout)
+                .change_context(Error::Formatter)?;
+            match status {
+                Some(status) => status,
+                None => {
+                    // Don't wait for the output threads, since anything the formatter started
+                    // may still hold its output open.
+                    format_process.kill().ok();
+                    format_process.wait().ok();
+                    return Err(Error::Formatter).attach_printable(format!(
+                        "Formatter did not finish within {}",
+                        humantime::format_duration(timeout)
+                    ));
+                }
+            }
+        }
+        None => format_process.wait().change_context(Error::Formatter)?,
+    };
+
+    if let Some(writer_thread) = writer_thread {
+        writer_thread
+            .join()
+            .expect("format writer thread")
+            .change_context(Error::Formatter)?;
+    }
+    let stdout = stdout.join().expect("format reader thread");
+    let stderr = stderr.join().expect("format reader thread");
+
+    let code = status.code().unwrap_or(0);
+    if !status.success() {
+        return Err(Error::Formatter)
+            .attach_printable(format!("Formatter exited with code {code}"))
+            .attach_printable(String::from_utf8(stderr).unwrap_or_default());
+    }
+
+    Ok(stdout)
+}
+
+fn read_in_thread(mut reader: impl Read + Send + 'static) -> std::thread::JoinHandle<Vec<u8>> {
+    std::thread::spawn(move || {
+        let mut output = Vec::new();
+        reader.read_to_end(&mut output).ok();
+        output
+    })
+}
@@ -1,11 +1,30 @@
 #[cfg(test)]
+#[allow(clippy::redundant_static_lifetimes)]
 mod test;
 
+mod builder;
+mod config;
+mod context;
+mod dependencies;
+mod depfile;
+mod diagnostics;
+mod format;
+mod formatter;
+mod manifest;
+mod placeholders;
+mod prune;
+mod report;
+mod resolve;
+mod rust_module;
+mod template_tests;
+mod validate;
+mod watch;
+
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
     io::Write,
     path::{Path, PathBuf},
-    process::Stdio,
+    time::{Duration, Instant},
 };
 
 use clap::Parser;
@@ -14,6 +33,24 @@ use itertools::Itertools;
 use rayon::prelude::*;
 use tera::Tera;
 
+pub use crate::{
+    builder::OptionsBuilder,
+    diagnostics::{
+        print_diagnostics, report_diagnostics, sarif_log, Diagnostic, MessageFormat, Severity,
+        SourceLocation, Span,
+    },
+    format::{Format, KeywordCase},
+    formatter::FormatterMode,
+    placeholders::PlaceholderStyle,
+    report::{
+        BuildReport, OutputFile, OutputStatus, RenderedQuery, TemplateReport, TestCaseReport,
+        TestReport,
+    },
+    validate::SqlDialect,
+};
+use crate::{diagnostics::print_status, formatter::FormatterCommand, manifest::QueryManifest};
+
+/// Options for [build]. Use [Options::builder] to create these from Rust code.
 #[derive(Debug, Default, Parser)]
 pub struct Options {
     /// Where to look for input files. This can be a glob. If omitted, the current directory is used.
@@ -21,25 +58,61 @@ pub struct Options {
     input: Option<PathBuf>,
 
     /// Where to write the output files. If omitted, output files are written to the same directory as the input files.
+    /// Otherwise, the directory structure of the input directory is recreated inside this directory.
     #[clap(short, long)]
     output: Option<PathBuf>,
 
+    /// Also load partials and macro files from this directory. Templates in it are not rendered.
+    /// This can be given multiple times. When more than one partial has the same name, those in
+    /// the input directory take precedence, followed by the directories in the order they were
+    /// given.
+    #[clap(long = "partials-dir")]
+    partials_dirs: Vec<PathBuf>,
+
+    /// Partials which don't come from a file, as pairs of name and contents. See
+    /// [Options::add_partial_source].
+    #[clap(skip)]
+    partial_sources: Vec<(String, String)>,
+
     /// Extra context to pass into the templates.
     #[clap(skip)]
     context: Option<tera::Context>,
 
+    /// Read template context from a JSON, TOML, or YAML file. This can be given multiple times, and
+    /// later files are merged on top of earlier ones.
+    #[clap(long = "context-file")]
+    context_files: Vec<PathBuf>,
+
+    /// Set a template context value, as `key=value`. Dotted keys such as `a.b=c` set nested values.
+    /// Values that are valid JSON are parsed as JSON, and anything else is used as a string.
+    /// These override values from context files.
+    #[clap(long = "var", value_parser = context::parse_var)]
+    vars: Vec<(String, String)>,
+
+    /// Context values from the `vars` table of a configuration file, which keep the types they
+    /// were given in the file. These are applied before [Options::vars].
+    #[clap(skip)]
+    config_vars: Vec<(String, serde_json::Value)>,
+
     /// Print output as files are processed.
     #[clap(short, long, action=clap::ArgAction::Count)]
     verbose: u8,
 
-    /// Print rerun-if-changed statements for build.rs.
+    /// Print rerun-if-changed statements for build.rs. These cover the template files, the
+    /// directories that were searched so that new templates are noticed, and the context and
+    /// configuration files.
     #[clap(long)]
     print_rerun_if_changed: bool,
 
     /// Traverse normally-ignored directories such as those in .gitignore.
-    #[clap(long)]
+    #[clap(long, overrides_with = "no_check_ignored_dirs")]
     check_ignored_dirs: bool,
 
+    /// Skip normally-ignored directories, even if the configuration file sets
+    /// `check-ignored-dirs`.
+    #[clap(long, overrides_with = "check_ignored_dirs")]
+    no_check_ignored_dirs: bool,
+
     /// Customize the header line that will be added to the generated files.
     /// The SQL comment prefix will be added automatically.
     #[clap(long)]
@@ -51,14 +124,191 @@ pub struct Options {
 
     /// Always write the output files, even if the rendered template is identical to the file's
     /// current contents.
-    #[clap(long)]
+    #[clap(long, overrides_with = "no_always_write")]
     always_write: bool,
 
+    /// Only write output files that have changed, even if the configuration file sets
+    /// `always-write`.
+    #[clap(long, overrides_with = "always_write")]
+    no_always_write: bool,
+
     /// If provided, format the files using this command.
     ///
-    /// The command should take output on stdin and return the formatted output on stdout.
+    /// The command should take output on stdin and return the formatted output on stdout. It is
+    /// split into arguments the way a shell would, and `{path}` and `{dialect}` in the arguments
+    /// are replaced with the path of the output file and the SQL dialect.
+    #[clap(short, long)]
+    formatter: Option<FormatterCommand>,
+
+    /// How the formatter receives the SQL. `stdin` runs it once for each file. `batch` writes all
+    /// the files to a temporary directory and runs it once with all of their paths, for
+    /// formatters that format files in place. The paths replace a `{paths}` argument, or are
+    /// added to the end of the command.
+    #[clap(long, value_enum)]
+    formatter_mode: Option<FormatterMode>,
+
+    /// Stop the formatter and fail if it runs for longer than this, such as `10s`.
+    #[clap(long, value_parser = humantime::parse_duration)]
+    formatter_timeout: Option<Duration>,
+
+    /// Pass this environment variable to the formatter. This can be given multiple times. When
+    /// any are given, the formatter only receives these variables and `PATH`, instead of the
+    /// whole environment.
+    #[clap(long = "formatter-env")]
+    formatter_env: Vec<String>,
+
+    /// The SQL dialect of the queries, which replaces `{dialect}` in the formatter command.
+    /// Defaults to the dialect given to `--validate`.
+    #[clap(long, value_enum)]
+    dialect: Option<SqlDialect>,
+
+    /// Format the files with the formatter built into sqlweld, instead of running a command.
+    #[clap(long, value_enum, conflicts_with = "formatter")]
+    format: Option<Format>,
+
+    /// The number of spaces for each level of indentation, when using the built-in formatter.
+    /// Defaults to 2.
+    #[clap(long)]
+    format_indent: Option<usize>,
+
+    /// How the built-in formatter writes SQL keywords. Defaults to `upper`.
+    #[clap(long, value_enum)]
+    keyword_case: Option<KeywordCase>,
+
+    /// The line width that the built-in formatter tries to keep within. Defaults to 80.
+    #[clap(long)]
+    line_width: Option<usize>,
+
+    /// Rewrite `$[name]` placeholders into the parameter style used by a database driver.
+    ///
+    /// The names of the parameters, in the order they should be bound, are listed in a comment at
+    /// the top of each generated file.
+    #[clap(long, value_enum)]
+    placeholders: Option<PlaceholderStyle>,
+
+    /// Check that each generated query parses as SQL in this dialect, after running the formatter.
+    /// Uncompiled `$[name]` placeholders are treated as parameters.
+    #[clap(long, value_enum)]
+    validate: Option<SqlDialect>,
+
+    /// Write a JSON manifest next to each generated file, listing the query's parameters in bind
+    /// order and the partials and macros that it uses.
+    #[clap(long, overrides_with = "no_manifest")]
+    manifest: bool,
+
+    /// Don't write manifests, even if the configuration file sets `manifest`.
+    #[clap(long, overrides_with = "manifest")]
+    no_manifest: bool,
+
+    /// Write a Makefile-style dependency file to this path, listing the templates, partials, and
+    /// context files that each output file was rendered from.
+    #[clap(long)]
+    depfile: Option<PathBuf>,
+
+    /// Write a Rust file to this path with a constant for each query, along with its parameters
+    /// and a `QUERIES` table to look queries up by name. This is meant to be used from `build.rs`
+    /// with a path in `OUT_DIR`, and then included with `include!`.
+    #[clap(long)]
+    rust_module: Option<PathBuf>,
+
+    /// After the initial build, keep running and re-render templates when they change.
+    ///
+    /// Only the changed templates, and the templates that use changed partials or macros, are
+    /// rendered again.
     #[clap(short, long)]
-    formatter: Option<String>,
+    watch: bool,
+
+    /// Render the templates and verify that the output files are up to date, without writing
+    /// anything. Fails if any output file is missing or has different contents.
+    #[clap(long, conflicts_with = "watch")]
+    check: bool,
+
+    /// Keep rendering the other templates when one fails, and report all the failures at the end.
+    #[clap(short, long)]
+    keep_going: bool,
+
+    /// Delete generated files whose templates no longer exist. Only files which start with the
+    /// generated file header are removed.
+    #[clap(long, overrides_with = "no_prune")]
+    prune: bool,
+
+    /// Don't delete stale generated files, even if the configuration file sets `prune`.
+    #[clap(long, overrides_with = "prune")]
+    no_prune: bool,
+
+    /// When pruning, list the files that would be removed instead of removing them.
+    #[clap(long)]
+    dry_run: bool,
+
+    /// Read defaults from this configuration file, instead of searching for a `sqlweld.toml`.
+    #[clap(long)]
+    config: Option<PathBuf>,
+
+    /// Don't read any configuration file.
+    #[clap(long, conflicts_with = "config")]
+    no_config: bool,
+
+    /// How to print errors and warnings. `json` prints one object per line and `sarif` prints a
+    /// SARIF log, both on stdout.
+    #[clap(long, value_enum, default_value_t)]
+    message_format: MessageFormat,
+}
+
+impl Options {
+    /// Fill in any options that were not set from the project's configuration file. This is either
+    /// the file given by the `config` option or, if that is not set, the first `sqlweld.toml` or
+    /// `Cargo.toml` with a `[package.metadata.sqlweld]` section found in the current directory or
+    /// one of its parents.
+    pub fn with_config_file(self) -> Result<Self, Report<Error>> {
+        let current_dir = std::env::current_dir().change_context(Error::Config)?;
+        self.with_config_file_from(&current_dir)
+    }
+
+    /// Like [Options::with_config_file], but search for the configuration file starting from
+    /// `dir` instead of the current directory. The `config` option is set to the file that was
+    /// used, if any.
+    pub fn with_config_file_from(mut self, dir: &Path) -> Result<Self, Report<Error>> {
+        if self.no_config {
+            return Ok(self);
+        }
+
+        let path = match self.config.clone() {
+            Some(path) => path,
+            None => match config::find_config_file(dir)? {
+                Some(path) => path,
+                None => return Ok(self),
+            },
+        };
+
+        self.config = Some(path.clone());
+        config::apply_config_file(self, &path)
+    }
+
+    /// Add a partial or macro file from a string instead of a file, such as one embedded in
+    /// another crate with `include_str!`. The name is what templates use to import the partial,
+    /// such as `auth/perm_check`.
+    ///
+    /// These partials have a lower precedence than those from files, so a partial in the input
+    /// directory or in [partials_dirs](OptionsBuilder::partials_dir) with the same name will be
+    /// used instead.
+    pub fn add_partial_source(&mut self, name: impl Into<String>, contents: impl Into<String>) {
+        self.partial_sources.push((name.into(), contents.into()));
+    }
+
+    /// The settings for the built-in formatter.
+    fn format_options(&self) -> format::FormatOptions {
+        let defaults = format::FormatOptions::default();
+        format::FormatOptions {
+            indent: self.format_indent.unwrap_or(defaults.indent),
+            keyword_case: self.keyword_case.unwrap_or(defaults.keyword_case),
+            line_width: self.line_width.unwrap_or(defaults.line_width),
+        }
+    }
+
+    /// How errors and warnings should be printed.
+    pub fn message_format(&self) -> MessageFormat {
+        self.message_format
+    }
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -75,14 +325,369 @@ pub enum Error {
     DuplicatePartial,
     #[error("Failed to run SQL formatter")]
     Formatter,
+    #[error("One or more templates failed")]
+    TemplateFailures,
+    #[error("Failed to watch for file changes")]
+    Watch,
+    #[error("Multiple templates would be written to the same output file")]
+    DuplicateOutput,
+    #[error("Some output files are out of date")]
+    OutOfDate,
+    #[error("Failed to read context file")]
+    ReadContext,
+    #[error("Context values have conflicting types")]
+    ContextConflict,
+    #[error("Failed to read configuration file")]
+    Config,
+    #[error("Failed to remove stale output file")]
+    Prune,
+    #[error("Stale output files can not be identified when the header is disabled")]
+    PruneWithoutHeader,
+    #[error("Failed to generate Rust module")]
+    RustModule,
+    #[error("Generated SQL is not valid")]
+    InvalidSql,
+    #[error("Failed to read template test file")]
+    ReadTest,
+    #[error("Template test failed")]
+    TestFailed,
+    #[error("One or more template tests failed")]
+    TestFailures,
 }
 
-pub fn build(options: Options) -> Result<(), Report<Error>> {
-    let input_dir = options
-        .input
-        .unwrap_or_else(|| std::env::current_dir().expect("getting current directory"));
+impl Error {
+    /// A short identifier for this kind of error, used in machine-readable output.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::ReadTemplate => "read-template",
+            Error::Render => "render",
+            Error::WriteResult => "write-result",
+            Error::InternalError => "internal",
+            Error::DuplicatePartial => "duplicate-partial",
+            Error::Formatter => "formatter",
+            Error::TemplateFailures => "template-failures",
+            Error::Watch => "watch",
+            Error::DuplicateOutput => "duplicate-output",
+            Error::OutOfDate => "out-of-date",
+            Error::ReadContext => "read-context",
+            Error::ContextConflict => "context-conflict",
+            Error::Config => "config",
+            Error::Prune => "prune",
+            Error::PruneWithoutHeader => "prune-without-header",
+            Error::RustModule => "rust-module",
+            Error::InvalidSql => "invalid-sql",
+            Error::ReadTest => "read-test",
+            Error::TestFailed => "test-failed",
+            Error::TestFailures => "test-failures",
+        }
+    }
+}
+
+/// Attached to an [Error::OutOfDate] report, listing the output files that are missing or differ
+/// from the rendered templates. Retrieve it with `report.downcast_ref::<StaleFiles>()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StaleFiles(pub Vec<PathBuf>);
+
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+enum TemplateType {
+    Macro,
+    Partial,
+    Normal,
+}
+
+const TEMPLATE_SUFFIX: &str = ".sql.tera";
+const MACRO_SUFFIX: &str = ".macros.sql.tera";
+const PARTIAL_SUFFIX: &str = ".partial.sql.tera";
+
+/// The directory that partials from [Options::add_partial_source] are placed in. It doesn't exist,
+/// but gives them a path for error messages.
+const PARTIAL_SOURCES_DIR: &str = "<partial sources>";
+
+fn template_type(path: &Path) -> TemplateType {
+    let p = path.to_string_lossy();
+    match p {
+        p if p.ends_with(MACRO_SUFFIX) => TemplateType::Macro,
+        p if p.ends_with(PARTIAL_SUFFIX) => TemplateType::Partial,
+        _ => TemplateType::Normal,
+    }
+}
+
+fn is_template_file(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|f| f.to_str())
+        .map(|f| f.ends_with(TEMPLATE_SUFFIX))
+        .unwrap_or(false)
+}
+
+/// The parsed templates, along with the information needed to update them when files change.
+struct TemplateSet {
+    input_dir: PathBuf,
+    /// Extra directories that partials and macro files are loaded from, in order of precedence.
+    partials_dirs: Vec<PathBuf>,
+    tera: Tera,
+    /// Partial and macro names, mapped to the file which defines them.
+    partials: HashMap<String, PathBuf>,
+    /// Partials which are hidden by another partial with the same name from a directory with
+    /// higher precedence, mapped to their names.
+    shadowed: BTreeMap<PathBuf, String>,
+    /// The contents of partials from [Options::add_partial_source], keyed by their paths in
+    /// [PARTIAL_SOURCES_DIR].
+    sources: HashMap<PathBuf, String>,
+    /// Every known template file, mapped to its template name.
+    files: BTreeMap<PathBuf, String>,
+    /// The parsed templates, before references to other templates are resolved. See
+    /// [TemplateSet::link].
+    parsed: HashMap<String, tera::Template>,
+}
+
+impl TemplateSet {
+    fn new(input_dir: PathBuf, partials_dirs: Vec<PathBuf>) -> Self {
+        TemplateSet {
+            input_dir,
+            partials_dirs,
+            tera: Tera::default(),
+            partials: HashMap::new(),
+            shadowed: BTreeMap::new(),
+            sources: HashMap::new(),
+            files: BTreeMap::new(),
+            parsed: HashMap::new(),
+        }
+    }
+
+    /// Read and parse all the template files at `paths`, along with the partials from
+    /// [Options::add_partial_source]. Files from the input directory should come before those from
+    /// [Options::partials_dirs].
+    fn load(
+        input_dir: PathBuf,
+        options: &Options,
+        paths: Vec<PathBuf>,
+    ) -> Result<Self, Report<Error>> {
+        let (set, errors) = Self::load_all(input_dir, options, paths);
+        match errors.into_iter().next() {
+            Some(e) => Err(e),
+            None => Ok(set),
+        }
+    }
+
+    /// Like [TemplateSet::load], but keep going after an error, and return every error along with
+    /// the templates that did load.
+    fn load_all(
+        input_dir: PathBuf,
+        options: &Options,
+        paths: Vec<PathBuf>,
+    ) -> (Self, Vec<Report<Error>>) {
+        let mut set = Self::new(input_dir, options.partials_dirs.clone());
+        let mut errors = Vec::new();
+
+        for path in &paths {
+            if let Err(e) = set.register(path) {
+                errors.push(e);
+            }
+        }
+
+        for (name, contents) in &options.partial_sources {
+            let path = partial_source_path(name);
+            set.sources.insert(path.clone(), contents.clone());
+            if let Err(e) = set.register(&path) {
+                errors.push(e);
+            }
+        }
+
+        // Shadowed partials are skipped.
+        let paths = set.files.keys().cloned().collect::<Vec<_>>();
+        for path in &paths {
+            if let Err(e) = set.parse(path) {
+                errors.push(set.load_error(e));
+            }
+        }
+
+        if let Err(e) = set.link() {
+            errors.push(e);
+        }
+        (set, errors)
+    }
+
+    /// Read and parse a template file which has already been registered.
+    fn parse(&mut self, path: &Path) -> Result<(), tera::Error> {
+        let name = self.files.get(path).cloned().unwrap_or_default();
+        let source = self
+            .read_source(path)
+            .map_err(|e| tera::Error::chain(format!("Failed to read template {:?}", path), e))?;
+        let template =
+            tera::Template::new(&name, Some(path.to_string_lossy().to_string()), &source)
+                .map_err(|e| tera::Error::chain(format!("Failed to parse {:?}", path), e))?;
+        self.parsed.insert(name, template);
+        Ok(())
+    }
+
+    /// Read the contents of a template file, or of a partial from [Options::add_partial_source].
+    fn read_source(&self, path: &Path) -> std::io::Result<String> {
+        match self.sources.get(path) {
+            Some(source) => Ok(source.clone()),
+            None => std::fs::read_to_string(path),
+        }
+    }
+
+    /// Convert an error from reading or parsing the templates into a report.
+    fn load_error(&self, error: tera::Error) -> Report<Error> {
+        let diagnostic = diagnostics::load_diagnostic(self, &error);
+        Report::new(error)
+            .change_context(Error::ReadTemplate)
+            .attach_printable(diagnostic)
+    }
+
+    /// The directory that `path` was found in, and its precedence. Lower numbers take precedence
+    /// over higher ones, with the input directory coming first.
+    fn root(&self, path: &Path) -> (usize, &Path) {
+        if self.sources.contains_key(path) {
+            return (self.partials_dirs.len() + 1, Path::new(PARTIAL_SOURCES_DIR));
+        }
+
+        if path.starts_with(&self.input_dir) {
+            return (0, &self.input_dir);
+        }
+
+        self.partials_dirs
+            .iter()
+            .enumerate()
+            .find(|(_, dir)| path.starts_with(dir))
+            .map(|(i, dir)| (i + 1, dir.as_path()))
+            .unwrap_or((0, &self.input_dir))
+    }
+
+    /// Figure out the template name for a file and record it, without reading the file. Returns
+    /// `None` if the file is a partial that is shadowed by another partial with the same name.
+    fn register(&mut self, path: &Path) -> Result<Option<String>, Report<Error>> {
+        if let Some(name) = self.files.get(path) {
+            return Ok(Some(name.clone()));
+        }
+
+        let (precedence, root) = self.root(path);
+        let template_name = path.strip_prefix(root).unwrap_or(path);
+
+        // Partials and macros are named by their path relative to the input directory, so that
+        // files with the same name in different directories don't conflict.
+        let typ = template_type(template_name);
+        let template_name = match typ {
+            TemplateType::Normal => template_name.to_string_lossy().to_string(),
+            TemplateType::Macro => resolve::partial_name(template_name, MACRO_SUFFIX),
+            TemplateType::Partial => resolve::partial_name(template_name, PARTIAL_SUFFIX),
+        };
+
+        if typ != TemplateType::Normal {
+            if let Some(existing) = self.partials.get(&template_name).cloned() {
+                let existing_precedence = self.root(&existing).0;
+                if existing_precedence == precedence {
+                    return Err(Error::DuplicatePartial)
+                        .attach_printable(existing.display().to_string())
+                        .attach_printable(path.display().to_string());
+                } else if existing_precedence < precedence {
+                    self.shadowed.insert(path.to_path_buf(), template_name);
+                    return Ok(None);
+                }
+
+                self.unregister(&existing);
+                self.shadowed.insert(existing, template_name.clone());
+            }
+
+            self.partials
+                .insert(template_name.clone(), path.to_path_buf());
+        }
+
+        self.files.insert(path.to_path_buf(), template_name.clone());
+        Ok(Some(template_name))
+    }
+
+    /// Forget about a template file that no longer exists.
+    fn unregister(&mut self, path: &Path) -> Option<String> {
+        if let Some(name) = self.shadowed.remove(path) {
+            return Some(name);
+        }
+
+        let name = self.files.remove(path)?;
+        if template_type(path) != TemplateType::Normal {
+            self.partials.remove(&name);
+        }
+        self.parsed.remove(&name);
+        self.tera.templates.remove(&name);
+        Some(name)
+    }
+
+    /// After the partial called `name` is removed, load the partial which it was shadowing, if
+    /// there is one.
+    fn restore_shadowed(&mut self, name: &str) -> Result<(), Report<Error>> {
+        let Some(path) = self
+            .shadowed
+            .iter()
+            .filter(|(_, shadowed_name)| shadowed_name.as_str() == name)
+            .map(|(path, _)| path.clone())
+            .min_by_key(|path| self.root(path).0)
+        else {
+            return Ok(());
+        };
+
+        self.shadowed.remove(&path);
+        self.register(&path)?;
+        self.parse(&path).map_err(|e| self.load_error(e))
+    }
+
+    /// Whether a partial comes from one of the [TemplateSet::partials_dirs] or
+    /// [Options::add_partial_source] instead of the input directory.
+    fn is_shared(&self, path: &Path) -> bool {
+        self.root(path).0 > 0
+    }
+
+    /// The templates that should be rendered to output files.
+    fn normal_templates(&self) -> impl Iterator<Item = (&Path, &str)> {
+        self.files
+            .iter()
+            .filter(|(path, _)| template_type(path) == TemplateType::Normal)
+            .map(|(path, name)| (path.as_path(), name.as_str()))
+    }
+}
+
+/// The path used to refer to a partial from [Options::add_partial_source]. If the name doesn't end
+/// with one of the partial or macro suffixes, it is treated as a partial.
+fn partial_source_path(name: &str) -> PathBuf {
+    let name = name.trim_start_matches('/');
+    let path = Path::new(PARTIAL_SOURCES_DIR).join(name);
+    if template_type(&path) == TemplateType::Normal {
+        Path::new(PARTIAL_SOURCES_DIR).join(format!("{name}{PARTIAL_SUFFIX}"))
+    } else {
+        path
+    }
+}
+
+/// Find the template files in the input directory, followed by the partials and macro files in
+/// [Options::partials_dirs] in order of precedence. Also returns every directory that was
+/// searched, sorted.
+fn find_all_templates(input_dir: &Path, options: &Options) -> (Vec<PathBuf>, Vec<PathBuf>) {
+    let (mut paths, mut dirs) = walk_template_files(input_dir, options);
+    paths.sort();
+
+    for partials_dir in &options.partials_dirs {
+        let (shared, shared_dirs) = walk_template_files(partials_dir, options);
+        paths.extend(
+            shared
+                .into_iter()
+                .filter(|path| template_type(path) != TemplateType::Normal)
+                .sorted(),
+        );
+        dirs.extend(shared_dirs);
+    }
+
+    dirs.sort();
+    dirs.dedup();
+    (paths, dirs)
+}
+
+fn find_template_files(input_dir: &Path, options: &Options) -> Vec<PathBuf> {
+    walk_template_files(input_dir, options).0
+}
 
-    let mut walker = ignore::WalkBuilder::new(&input_dir);
+/// Find the template files in `input_dir`, along with the directories that were searched.
+fn walk_template_files(input_dir: &Path, options: &Options) -> (Vec<PathBuf>, Vec<PathBuf>) {
+    let mut walker = ignore::WalkBuilder::new(input_dir);
 
     walker
         .hidden(!options.check_ignored_dirs)
@@ -100,7 +705,7 @@ pub fn build(options: Options) -> Result<(), Report<Error>> {
                 return false;
             };
 
-            filename.ends_with(".sql.tera")
+            filename.ends_with(TEMPLATE_SUFFIX)
         });
 
     let walker = walker.build_parallel();
@@ -117,12 +722,13 @@ pub fn build(options: Options) -> Result<(), Report<Error>> {
                     return ignore::WalkState::Skip;
                 };
 
-                if !result.file_type().map(|f| f.is_file()).unwrap_or(false) {
+                let is_dir = result.file_type().map(|f| f.is_dir()).unwrap_or(false);
+                if !is_dir && !result.file_type().map(|f| f.is_file()).unwrap_or(false) {
                     return ignore::WalkState::Continue;
                 }
 
                 let path = result.path().to_owned();
-                match file_tx.send(path) {
+                match file_tx.send((path, is_dir)) {
                     Ok(_) => ignore::WalkState::Continue,
                     Err(_) => ignore::WalkState::Quit,
                 }
@@ -130,193 +736,696 @@ pub fn build(options: Options) -> Result<(), Report<Error>> {
         });
     });
 
-    #[derive(PartialEq, Eq, Copy, Clone)]
-    enum TemplateType {
-        Macro,
-        Partial,
-        Normal,
-    }
-
-    const MACRO_SUFFIX: &str = ".macros.sql.tera";
-    const PARTIAL_SUFFIX: &str = ".partial.sql.tera";
-    fn template_type(path: &Path) -> TemplateType {
-        let p = path.to_string_lossy();
-        match p {
-            p if p.ends_with(MACRO_SUFFIX) => TemplateType::Macro,
-            p if p.ends_with(PARTIAL_SUFFIX) => TemplateType::Partial,
-            _ => TemplateType::Normal,
-        }
-    }
+    let (dirs, files): (Vec<_>, Vec<_>) = file_rx.into_iter().partition(|(_, is_dir)| *is_dir);
+    (
+        files.into_iter().map(|(path, _)| path).collect(),
+        dirs.into_iter().map(|(path, _)| path).collect(),
+    )
+}
 
-    let mut tera = Tera::default();
-    let mut partials: HashMap<String, PathBuf> = HashMap::new();
-    let mut templates = vec![];
+/// The files and directories that `build.rs` should watch: the template files, the directories
+/// that were searched for templates so that new files are noticed, and the context and
+/// configuration files. These are sorted so that the output is the same on every run.
+fn rerun_if_changed_paths(
+    options: &Options,
+    templates: &[PathBuf],
+    dirs: &[PathBuf],
+) -> Vec<PathBuf> {
+    templates
+        .iter()
+        .chain(dirs)
+        .chain(&options.context_files)
+        .chain(&options.config)
+        .cloned()
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect()
+}
 
-    for path in file_rx {
-        if options.print_rerun_if_changed {
+pub fn build(options: Options) -> Result<BuildReport, Report<Error>> {
+    let start = Instant::now();
+    let input_dir = options
+        .input
+        .clone()
+        .unwrap_or_else(|| std::env::current_dir().expect("getting current directory"));
+
+    let (paths, dirs) = find_all_templates(&input_dir, &options);
+
+    if options.print_rerun_if_changed {
+        for path in rerun_if_changed_paths(&options, &paths, &dirs) {
             println!("cargo:rerun-if-changed={}", path.display());
         }
+    }
 
-        let template_name = path.strip_prefix(&input_dir).unwrap();
+    if options.watch {
+        watch::watch(options, input_dir, paths)?;
+        return Ok(BuildReport::default());
+    }
 
-        let typ = template_type(&template_name);
-        let template_name = match typ {
-            TemplateType::Normal => template_name.to_string_lossy().to_string(),
-            TemplateType::Macro => template_name
-                .file_name()
-                .unwrap()
-                .to_string_lossy()
-                .strip_suffix(MACRO_SUFFIX)
-                .unwrap()
-                .to_string(),
-            TemplateType::Partial => template_name
-                .file_name()
-                .unwrap()
-                .to_string_lossy()
-                .strip_suffix(PARTIAL_SUFFIX)
-                .unwrap()
-                .to_string(),
-        };
+    let templates = TemplateSet::load(input_dir, &options, paths)?;
 
-        if typ != TemplateType::Normal {
-            if let Some(existing) = partials.get(&template_name) {
-                return Err(Error::DuplicatePartial)
-                    .attach_printable(existing.display().to_string())
-                    .attach_printable(path.display().to_string());
-            }
+    let mut report = BuildReport {
+        templates: templates.files.keys().cloned().collect(),
+        partials: templates
+            .partials
+            .iter()
+            .map(|(name, path)| (name.clone(), path.clone()))
+            .collect(),
+        ..Default::default()
+    };
+
+    if templates.tera.get_template_names().next().is_none() {
+        report.warnings.push(Diagnostic::warning(
+            "no-templates",
+            format!("No templates found in {}", templates.input_dir.display()),
+            &templates.input_dir,
+        ));
+    } else {
+        report.rendered = render_all_templates(&options, &templates)?;
+    }
 
-            partials.insert(template_name.clone(), path.clone());
+    report.rendered.sort_by(|a, b| a.template.cmp(&b.template));
+    report.rust_module = rust_module::write_rust_module(&options, &report.rendered)?;
+    report.depfile = depfile::write_depfile(&options, &report.rendered, &report.rust_module)?;
+
+    report
+        .warnings
+        .extend(unused_partials(&templates).into_iter().map(|path| {
+            Diagnostic::warning(
+                "unused-partial",
+                format!("{} is not used by any template", path.display()),
+                path,
+            )
+        }));
+
+    if options.check {
+        let stale = report
+            .outputs()
+            .chain(report.rust_module.iter())
+            .filter(|output| output.status == OutputStatus::OutOfDate)
+            .map(|output| output.path.clone())
+            .sorted()
+            .collect::<Vec<_>>();
+
+        if stale.is_empty() {
+            report.elapsed = start.elapsed();
+            return Ok(report);
         }
 
-        templates.push((path, Some(template_name)));
+        let mut error = Report::new(Error::OutOfDate);
+        for path in &stale {
+            error = error.attach_printable(path.display().to_string());
+        }
+        return Err(error.attach(StaleFiles(stale)));
     }
 
-    tera.add_template_files(templates.clone())
-        .change_context(Error::ReadTemplate)?;
+    if options.prune {
+        report.pruned = prune::prune(&options, &templates)?;
+    }
 
-    if tera.get_template_names().next().is_none() {
-        if options.verbose >= 1 {
-            println!("No templates found");
+    report.elapsed = start.elapsed();
+    Ok(report)
+}
+
+/// Render every normal template. With [Options::keep_going], the failures from all the templates
+/// are combined into one error.
+fn render_all_templates(
+    options: &Options,
+    templates: &TemplateSet,
+) -> Result<Vec<TemplateReport>, Report<Error>> {
+    check_output_paths(options, templates)?;
+
+    let context = context::build_context(options)?;
+
+    let normal_templates = templates.normal_templates().collect::<Vec<_>>();
+    let results = render_templates(options, templates, &context, &normal_templates)?;
+    if options.keep_going {
+        let (rendered, failures): (Vec<_>, Vec<_>) =
+            results.into_iter().partition_map(|result| match result {
+                Ok(rendered) => itertools::Either::Left(rendered),
+                Err(failure) => itertools::Either::Right(failure),
+            });
+
+        if !failures.is_empty() {
+            return Err(combine_failures(failures));
         }
-        return Ok(());
+
+        Ok(rendered)
+    } else {
+        results
+            .into_iter()
+            .map(|result| result.map_err(|(_, e)| e))
+            .collect()
     }
+}
 
-    let context = options.context.unwrap_or_default();
+/// Merge the errors from multiple templates into a single report, ordered by template path.
+fn combine_failures(mut failures: Vec<(PathBuf, Report<Error>)>) -> Report<Error> {
+    failures.sort_by(|a, b| a.0.cmp(&b.0));
+    let count = failures.len();
 
-    let extension = options.extension.as_deref().unwrap_or("sql");
+    let mut failures = failures.into_iter().map(|(_, e)| e);
+    let mut combined = failures.next().expect("at least one failure");
+    for failure in failures {
+        combined.extend_one(failure);
+    }
+
+    combined
+        .change_context(Error::TemplateFailures)
+        .attach_printable(if count == 1 {
+            "1 template failed".to_string()
+        } else {
+            format!("{count} templates failed")
+        })
+}
+
+/// Find the partials and macro files that aren't used by any template that gets rendered. Shared
+/// partials from [Options::partials_dirs] are not included, since they are expected to only be
+/// used by some of the projects that load them.
+fn unused_partials(templates: &TemplateSet) -> Vec<&Path> {
+    let used = templates
+        .normal_templates()
+        .flat_map(|(_, name)| dependencies::template_dependencies(&templates.tera, name))
+        .collect::<HashSet<_>>();
 
     templates
-        .into_par_iter()
-        .filter(|(path, _)| template_type(path) == TemplateType::Normal)
-        .try_for_each(|(path, name)| {
-            let name = name.unwrap();
-            let output = tera
-                .render(&name, &context)
-                .change_context(Error::Render)
-                .attach_printable_lazy(|| path.display().to_string())?;
-
-            let template_base_name = path
-                .file_name()
-                .and_then(|s| s.to_str())
-                .unwrap_or_default()
-                .strip_suffix(".sql.tera")
-                .ok_or(Error::InternalError)
-                .attach_printable_lazy(|| {
-                    format!(
-                        "Template path did not end in .sql.tera: {}",
-                        path.display().to_string()
-                    )
-                })?;
-
-            let output_filename = format!("{template_base_name}.{extension}");
-            let output_path = if let Some(output) = options.output.as_ref() {
-                output.join(output_filename)
-            } else {
-                path.with_file_name(output_filename)
-            };
+        .partials
+        .iter()
+        .filter(|(name, _)| !used.contains(*name))
+        .filter(|(_, path)| !templates.is_shared(path))
+        .map(|(_, path)| path.as_path())
+        .sorted()
+        .collect()
+}
 
-            let header = options
-                .header
-                .as_deref()
-                .unwrap_or("Autogenerated by sqlweld");
-
-            let header_lines = header
-                .split(['\n', '\r'])
-                .map(|s| s.trim())
-                .filter(|s| !s.is_empty())
-                .map(|s| format!("-- {}", s))
-                .join("\n");
-
-            let output = if header_lines.is_empty() {
-                output
-            } else {
-                format!("{}\n\n{}", header_lines, output)
-            };
+/// Figure out where the output for the template at `path` should be written. When an output
+/// directory is set, the template's location relative to the input directory is preserved.
+fn output_path(options: &Options, input_dir: &Path, path: &Path) -> Result<PathBuf, Report<Error>> {
+    let template_base_name = path
+        .file_name()
+        .and_then(|s| s.to_str())
+        .unwrap_or_default()
+        .strip_suffix(TEMPLATE_SUFFIX)
+        .ok_or(Error::InternalError)
+        .attach_printable_lazy(|| {
+            format!("Template path did not end in .sql.tera: {}", path.display())
+        })?;
 
-            let output = if let Some(formatter) = options.formatter.as_ref() {
-                let mut format_process = std::process::Command::new(formatter)
-                    .stdin(Stdio::piped())
-                    .stdout(Stdio::piped())
-                    .stderr(Stdio::piped())
-                    .spawn()
-                    .change_context(Error::Formatter)?;
-
-                let mut stdin = format_process.stdin.take().ok_or(Error::Formatter)?;
-                let writer_thread = std::thread::spawn(move || {
-                    stdin
-                        .write_all(output.as_bytes())
-                        .change_context(Error::Formatter)
-                });
-
-                let result = format_process
-                    .wait_with_output()
-                    .change_context(Error::Formatter)?;
-
-                writer_thread
-                    .join()
-                    .expect("format writer thread")
-                    .change_context(Error::Formatter)?;
-
-                let code = result.status.code().unwrap_or(0);
-                if !result.status.success() {
-                    return Err(Error::Formatter)
-                        .attach_printable(format!("Formatter exited with code {code}"))
-                        .attach_printable(String::from_utf8(result.stderr).unwrap_or_default());
-                }
+    let extension = options.extension.as_deref().unwrap_or("sql");
+    let output_filename = format!("{template_base_name}.{extension}");
+    let output_path = if let Some(output) = options.output.as_ref() {
+        let relative_dir = path
+            .parent()
+            .and_then(|parent| parent.strip_prefix(input_dir).ok())
+            .unwrap_or(Path::new(""));
+        output.join(relative_dir).join(output_filename)
+    } else {
+        path.with_file_name(output_filename)
+    };
 
-                let output = result.stdout;
+    Ok(output_path)
+}
 
-                String::from_utf8(output).change_context(Error::Formatter)?
-            } else {
-                output
-            };
+/// Make sure that no two templates will be written to the same output file.
+fn check_output_paths(options: &Options, templates: &TemplateSet) -> Result<(), Report<Error>> {
+    let mut seen: HashMap<PathBuf, &Path> = HashMap::new();
+    for (path, _) in templates.normal_templates() {
+        let output = output_path(options, &templates.input_dir, path)?;
+        if let Some(existing) = seen.get(&output) {
+            return Err(Error::DuplicateOutput)
+                .attach_printable(format!("Output file: {}", output.display()))
+                .attach_printable(existing.display().to_string())
+                .attach_printable(path.display().to_string());
+        }
 
-            if !options.always_write {
-                if let Ok(existing) = std::fs::read_to_string(&output_path) {
-                    if existing == output {
-                        if options.verbose >= 3 {
-                            println!(
-                                "Skipping {} because it did not change",
-                                output_path.display()
-                            );
-                        }
-                        return Ok(());
-                    }
-                }
-            }
+        seen.insert(output, path);
+    }
 
-            if options.verbose >= 1 {
-                println!("Writing {}", output_path.display());
-            }
+    Ok(())
+}
+
+/// Remove generated files whose templates no longer exist, without rendering anything. If
+/// [Options::dry_run] is set, the files are only listed. Returns the stale files that were found.
+pub fn clean(options: Options) -> Result<Vec<PathBuf>, Report<Error>> {
+    let input_dir = options
+        .input
+        .clone()
+        .unwrap_or_else(|| std::env::current_dir().expect("getting current directory"));
+
+    let mut templates = TemplateSet::new(input_dir, Vec::new());
+    for path in find_template_files(&templates.input_dir, &options) {
+        templates.register(&path)?;
+    }
+
+    prune::prune(&options, &templates)
+}
 
-            write_file(&output_path, &output)?;
+/// Render templates and partials with the cases in their test files, and check the output. The
+/// tests for `get_some_objects.sql.tera` are in `get_some_objects.sql.tera.test.toml`. Each case
+/// is rendered with the same partials and context as [build], with the case's context merged on
+/// top, and is compared to the rendered template before the header, placeholders, and formatter
+/// are applied.
+///
+/// If any case fails, the error has a [Diagnostic] for each failure and the [TestReport] attached.
+/// Retrieve the report with `report.downcast_ref::<TestReport>()`.
+pub fn run_tests(options: Options) -> Result<TestReport, Report<Error>> {
+    let start = Instant::now();
+    let input_dir = options
+        .input
+        .clone()
+        .unwrap_or_else(|| std::env::current_dir().expect("getting current directory"));
+
+    let (paths, _) = find_all_templates(&input_dir, &options);
+    let templates = TemplateSet::load(input_dir, &options, paths)?;
+    let context = context::build_context(&options)?.into_json();
+
+    let mut report = TestReport::default();
+    let tested = templates
+        .files
+        .iter()
+        .filter(|(path, _)| template_type(path) != TemplateType::Macro)
+        .map(|(path, name)| (path, name, template_tests::test_file_path(path)))
+        .filter(|(_, _, test_file)| test_file.is_file())
+        .sorted();
+    for (path, name, test_file) in tested {
+        report.cases.extend(template_tests::run_test_file(
+            &templates, &context, path, name, &test_file,
+        )?);
+    }
+    report.elapsed = start.elapsed();
+
+    let failures = report
+        .cases
+        .iter()
+        .filter(|case| !case.passed())
+        .map(|case| {
+            Report::new(Error::TestFailed).attach_printable(diagnostics::test_diagnostic(case))
+        })
+        .collect::<Vec<_>>();
+
+    let count = failures.len();
+    let mut failures = failures.into_iter();
+    let Some(mut combined) = failures.next() else {
+        return Ok(report);
+    };
+    for failure in failures {
+        combined.extend_one(failure);
+    }
 
-            Ok::<_, Report<Error>>(())
+    Err(combined
+        .change_context(Error::TestFailures)
+        .attach_printable(format!("{count} of {} tests failed", report.cases.len()))
+        .attach(report))
+}
+
+/// Render a single template without writing any files, using the same partials, context, and
+/// output processing as [build]. `template` is either relative to the input directory, or a path
+/// inside it. If no input directory is set, the template's directory is used. This is useful for
+/// embedding queries at compile time.
+pub fn render_query(options: &Options, template: &Path) -> Result<RenderedQuery, Report<Error>> {
+    let input_dir = options
+        .input
+        .clone()
+        .or_else(|| {
+            template
+                .parent()
+                .filter(|dir| !dir.as_os_str().is_empty())
+                .map(|dir| dir.to_path_buf())
+        })
+        .unwrap_or_else(|| std::env::current_dir().expect("getting current directory"));
+
+    let (paths, _) = find_all_templates(&input_dir, options);
+
+    let templates = TemplateSet::load(input_dir, options, paths)?;
+
+    let path = if template.starts_with(&templates.input_dir) {
+        template.to_path_buf()
+    } else {
+        templates.input_dir.join(template)
+    };
+    let name = templates
+        .files
+        .get(&path)
+        .filter(|_| template_type(&path) == TemplateType::Normal)
+        .ok_or(Error::ReadTemplate)
+        .attach_printable_lazy(|| {
+            format!(
+                "{} is not a template in {}",
+                path.display(),
+                templates.input_dir.display()
+            )
         })?;
 
+    let context = context::build_context(options)?;
+    let rendered = render_sql(options, &templates, &context, &path, name)?;
+
+    Ok(RenderedQuery {
+        name: manifest::query_name(name),
+        sql: rendered.sql,
+        parameters: rendered.parameters,
+        files: input_files(options, &templates, &path, name),
+    })
+}
+
+/// The files that the template at `path` is rendered from: the template itself, the templates,
+/// partials, and macro files that it uses, and the context and configuration files.
+///
+/// The templates come from the `extends`, `import`, and `include` tags, including those in
+/// branches that don't run, so this can list more than a particular rendering reads.
+fn input_files(
+    options: &Options,
+    templates: &TemplateSet,
+    path: &Path,
+    name: &str,
+) -> Vec<PathBuf> {
+    let mut files = vec![path.to_path_buf()];
+    files.extend(
+        dependencies::template_dependencies(&templates.tera, name)
+            .iter()
+            .filter_map(|dep| templates.template_path(dep))
+            .filter(|dep| !templates.sources.contains_key(*dep))
+            .map(|dep| dep.to_path_buf()),
+    );
+    files.extend(options.context_files.iter().cloned());
+    files.extend(options.config.iter().cloned());
+    files
+}
+
+/// The comment lines placed at the top of each generated file.
+fn header_lines(options: &Options) -> String {
+    header_comment(options, "--")
+}
+
+/// The header, as comment lines starting with `prefix`.
+fn header_comment(options: &Options, prefix: &str) -> String {
+    let header = options
+        .header
+        .as_deref()
+        .unwrap_or("Autogenerated by sqlweld");
+
+    header
+        .split(['\n', '\r'])
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .map(|s| format!("{prefix} {}", s))
+        .join("\n")
+}
+
+/// The contents of a generated SQL file.
+struct RenderedSql {
+    sql: String,
+    parameters: Vec<String>,
+    render_time: std::time::Duration,
+    format_time: Option<std::time::Duration>,
+}
+
+/// The result of rendering a template, with the template's path on failure.
+type TemplateResult = Result<TemplateReport, (PathBuf, Report<Error>)>;
+
+/// Render each of `list`, and write the output files. In batch mode the formatter is run once
+/// for all of the templates. Failures are returned separately for each template, except when the
+/// batch formatter fails, which fails all of them.
+fn render_templates(
+    options: &Options,
+    templates: &TemplateSet,
+    context: &tera::Context,
+    list: &[(&Path, &str)],
+) -> Result<Vec<TemplateResult>, Report<Error>> {
+    let batch_formatter = options.formatter.as_ref().filter(|_| {
+        options.format.is_none() && options.formatter_mode == Some(FormatterMode::Batch)
+    });
+    let Some(formatter) = batch_formatter else {
+        return Ok(list
+            .par_iter()
+            .map(|(path, name)| {
+                render_template(options, templates, context, path, name)
+                    .map_err(|e| (path.to_path_buf(), e))
+            })
+            .collect());
+    };
+
+    let rendered = list
+        .par_iter()
+        .map(|(path, name)| {
+            let output_path = output_path(options, &templates.input_dir, path)?;
+            let rendered = render_unformatted(options, templates, context, path, name)?;
+            Ok((output_path, rendered))
+        })
+        .collect::<Vec<Result<_, Report<Error>>>>();
+
+    let files = rendered
+        .iter()
+        .filter_map(|result| result.as_ref().ok())
+        .map(|(output_path, rendered)| (output_path.as_path(), rendered.sql.as_str()))
+        .collect::<Vec<_>>();
+    let format_start = Instant::now();
+    let mut formatted = formatter::run_batch_formatter(options, formatter, &files)?.into_iter();
+    let format_time = format_start.elapsed();
+
+    let results = list
+        .iter()
+        .zip(rendered)
+        .map(|((path, name), rendered)| {
+            rendered
+                .and_then(|(output_path, mut rendered)| {
+                    rendered.sql = formatted.next().expect("formatted output for each file");
+                    rendered.format_time = Some(format_time);
+                    validate_rendered(options, templates, path, name, &output_path, &rendered)?;
+                    write_template(options, templates, path, name, output_path, rendered)
+                })
+                .map_err(|e| (path.to_path_buf(), e))
+        })
+        .collect();
+    Ok(results)
+}
+
+fn render_template(
+    options: &Options,
+    templates: &TemplateSet,
+    context: &tera::Context,
+    path: &Path,
+    name: &str,
+) -> Result<TemplateReport, Report<Error>> {
+    let rendered = render_sql(options, templates, context, path, name)?;
+    let output_path = output_path(options, &templates.input_dir, path)?;
+    write_template(options, templates, path, name, output_path, rendered)
+}
+
+/// Write the output file for a rendered template, along with its manifest.
+fn write_template(
+    options: &Options,
+    templates: &TemplateSet,
+    path: &Path,
+    name: &str,
+    output_path: PathBuf,
+    rendered: RenderedSql,
+) -> Result<TemplateReport, Report<Error>> {
+    let mut outputs = Vec::with_capacity(2);
+    if options.manifest {
+        let manifest = QueryManifest::new(
+            &templates.tera,
+            name,
+            options.placeholders,
+            rendered.parameters.clone(),
+        );
+        outputs.push(sync_file(
+            options,
+            manifest::manifest_path(&output_path),
+            &manifest.to_json(),
+        )?);
+    }
+
+    outputs.push(sync_file(options, output_path, &rendered.sql)?);
+    Ok(TemplateReport {
+        template: path.to_path_buf(),
+        name: manifest::query_name(name),
+        inputs: input_files(options, templates, path, name),
+        parameters: rendered.parameters,
+        sql: rendered.sql,
+        outputs,
+        render_time: rendered.render_time,
+        format_time: rendered.format_time,
+    })
+}
+
+/// Render a template and process it into the contents of its output file.
+fn render_sql(
+    options: &Options,
+    templates: &TemplateSet,
+    context: &tera::Context,
+    path: &Path,
+    name: &str,
+) -> Result<RenderedSql, Report<Error>> {
+    let mut rendered = render_unformatted(options, templates, context, path, name)?;
+    let output_path = output_path(options, &templates.input_dir, path)?;
+
+    let format_start = Instant::now();
+    let formatted = if options.format == Some(Format::Builtin) {
+        Some(format::format_sql(&rendered.sql, &options.format_options()))
+    } else if let Some(formatter) = options.formatter.as_ref() {
+        let sql = std::mem::take(&mut rendered.sql);
+        let formatted = if options.formatter_mode == Some(FormatterMode::Batch) {
+            formatter::run_batch_formatter(options, formatter, &[(&output_path, &sql)])
+                .map(|mut formatted| formatted.remove(0))
+        } else {
+            formatter::run_formatter(options, formatter, &output_path, sql)
+        };
+        Some(formatted.attach_printable_lazy(|| path.display().to_string())?)
+    } else {
+        None
+    };
+
+    if let Some(sql) = formatted {
+        rendered.sql = sql;
+        rendered.format_time = Some(format_start.elapsed());
+    }
+
+    validate_rendered(options, templates, path, name, &output_path, &rendered)?;
+    Ok(rendered)
+}
+
+/// Render a template, compile its placeholders, and add the header, without formatting it.
+fn render_unformatted(
+    options: &Options,
+    templates: &TemplateSet,
+    context: &tera::Context,
+    path: &Path,
+    name: &str,
+) -> Result<RenderedSql, Report<Error>> {
+    let render_start = Instant::now();
+    let output = templates.tera.render(name, context).map_err(|e| {
+        let diagnostic = diagnostics::render_diagnostic(templates, name, &e);
+        Report::new(e)
+            .change_context(Error::Render)
+            .attach_printable(path.display().to_string())
+            .attach_printable(diagnostic)
+    })?;
+
+    let (output, parameters) = match options.placeholders {
+        Some(style) => {
+            let compiled = placeholders::compile_placeholders(&output, style);
+            (compiled.sql, compiled.parameters)
+        }
+        None => {
+            let names = placeholders::placeholder_names(&output);
+            (output, names)
+        }
+    };
+
+    let mut header_lines = header_lines(options);
+    if options.placeholders.is_some() && !parameters.is_empty() {
+        if !header_lines.is_empty() {
+            header_lines.push('\n');
+        }
+        header_lines.push_str(&format!("-- Parameters: {}", parameters.join(", ")));
+    }
+
+    let output = if header_lines.is_empty() {
+        output
+    } else {
+        format!("{}\n\n{}", header_lines, output)
+    };
+
+    Ok(RenderedSql {
+        sql: output,
+        parameters,
+        render_time: render_start.elapsed(),
+        format_time: None,
+    })
+}
+
+/// Check that the formatted SQL parses, if [Options::validate] is set.
+fn validate_rendered(
+    options: &Options,
+    templates: &TemplateSet,
+    path: &Path,
+    name: &str,
+    output_path: &Path,
+    rendered: &RenderedSql,
+) -> Result<(), Report<Error>> {
+    let Some(dialect) = options.validate else {
+        return Ok(());
+    };
+
+    if let Err(e) = validate::validate_sql(&rendered.sql, dialect, options.placeholders) {
+        let diagnostic =
+            diagnostics::sql_diagnostic(templates, name, output_path, &rendered.sql, &e);
+        return Err(Report::new(Error::InvalidSql)
+            .attach_printable(path.display().to_string())
+            .attach_printable(diagnostic));
+    }
+
     Ok(())
 }
 
+/// Write `contents` to `output_path` if it differs from what is there. In check mode, only
+/// compare them.
+fn sync_file(
+    options: &Options,
+    output_path: PathBuf,
+    output: &str,
+) -> Result<OutputFile, Report<Error>> {
+    let unchanged = std::fs::read_to_string(&output_path)
+        .map(|existing| existing == output)
+        .unwrap_or(false);
+
+    if options.check {
+        if unchanged {
+            return Ok(OutputFile {
+                path: output_path,
+                status: OutputStatus::Unchanged,
+            });
+        }
+
+        if options.verbose >= 1 {
+            print_status(
+                options.message_format,
+                format!("{} is out of date", output_path.display()),
+            );
+        }
+        return Ok(OutputFile {
+            path: output_path,
+            status: OutputStatus::OutOfDate,
+        });
+    }
+
+    if unchanged && !options.always_write {
+        if options.verbose >= 3 {
+            print_status(
+                options.message_format,
+                format!(
+                    "Skipping {} because it did not change",
+                    output_path.display()
+                ),
+            );
+        }
+        return Ok(OutputFile {
+            path: output_path,
+            status: OutputStatus::Unchanged,
+        });
+    }
+
+    if options.verbose >= 1 {
+        print_status(
+            options.message_format,
+            format!("Writing {}", output_path.display()),
+        );
+    }
+
+    if let Some(parent) = output_path.parent() {
+        std::fs::create_dir_all(parent)
+            .change_context(Error::WriteResult)
+            .attach_printable_lazy(|| parent.display().to_string())?;
+    }
+
+    write_file(&output_path, output)?;
+
+    Ok(OutputFile {
+        path: output_path,
+        status: OutputStatus::Written,
+    })
+}
+
 fn atomic_write_file(path: &Path, contents: &str) -> Result<(), std::io::Error> {
     let mut temp = tempfile::NamedTempFile::new()?;
     temp.write_all(contents.as_bytes())?;
@@ -1,7 +1,27 @@
 use std::panic::Location;
 
-use clap::Parser;
-use sqlweld::{build, Error, Options};
+use clap::{Parser, Subcommand};
+use sqlweld::{
+    build, clean, print_diagnostics, report_diagnostics, run_tests, Error, MessageFormat, Options,
+};
+
+#[derive(Debug, Parser)]
+struct Cli {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
+    #[clap(flatten)]
+    options: Options,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Remove generated files whose templates no longer exist, without rendering anything.
+    Clean,
+    /// Render templates and partials with the cases in their `.test.toml` files, and check the
+    /// output.
+    Test,
+}
 
 fn main() -> Result<(), error_stack::Report<Error>> {
     #[cfg(debug_assertions)]
@@ -13,6 +33,31 @@ fn main() -> Result<(), error_stack::Report<Error>> {
         error_stack::Report::install_debug_hook::<Location>(|_, _| {});
     }
 
-    let options = Options::parse();
-    build(options)
+    let cli = Cli::parse();
+    let format = cli.options.message_format();
+    let result = cli
+        .options
+        .with_config_file()
+        .and_then(|options| match cli.command {
+            Some(Command::Clean) => clean(options).map(|_| Vec::new()),
+            Some(Command::Test) => run_tests(options).map(|report| {
+                if format == MessageFormat::Human {
+                    println!("{report}");
+                }
+                Vec::new()
+            }),
+            None => build(options).map(|report| report.warnings),
+        });
+
+    match (format, result) {
+        (_, Ok(warnings)) => {
+            print_diagnostics(format, &warnings);
+            Ok(())
+        }
+        (MessageFormat::Human, Err(e)) => Err(e),
+        (_, Err(e)) => {
+            print_diagnostics(format, &report_diagnostics(&e));
+            std::process::exit(1);
+        }
+    }
 }
@@ -0,0 +1,61 @@
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use crate::{dependencies::template_dependencies, PlaceholderStyle, TEMPLATE_SUFFIX};
+
+/// Information about a generated query, written next to the output file when
+/// [Options::manifest](crate::Options) is set.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub(crate) struct QueryManifest {
+    /// The name of the query, which is the template path without the `.sql.tera` suffix.
+    pub name: String,
+    /// The template's path, relative to the input directory.
+    pub template: String,
+    /// The placeholder style that the query's parameters were compiled to, if any.
+    pub placeholder_style: Option<PlaceholderStyle>,
+    /// The parameter names, in the order that they should be bound.
+    pub parameters: Vec<String>,
+    /// The partials, macro files, and other templates that this query uses.
+    pub dependencies: Vec<String>,
+}
+
+impl QueryManifest {
+    pub(crate) fn new(
+        tera: &tera::Tera,
+        template_name: &str,
+        placeholder_style: Option<PlaceholderStyle>,
+        parameters: Vec<String>,
+    ) -> Self {
+        QueryManifest {
+            name: query_name(template_name),
+            template: template_name.replace('\\', "/"),
+            placeholder_style,
+            parameters,
+            dependencies: template_dependencies(tera, template_name)
+                .into_iter()
+                .collect(),
+        }
+    }
+
+    pub(crate) fn to_json(&self) -> String {
+        let mut json = serde_json::to_string_pretty(self).expect("serializing manifest");
+        json.push('\n');
+        json
+    }
+}
+
+/// The name of a query, which is the template path without the `.sql.tera` suffix.
+pub(crate) fn query_name(template_name: &str) -> String {
+    template_name
+        .strip_suffix(TEMPLATE_SUFFIX)
+        .unwrap_or(template_name)
+        .replace('\\', "/")
+}
+
+/// The path of the manifest for the output file at `output_path`.
+pub(crate) fn manifest_path(output_path: &Path) -> PathBuf {
+    let mut path = output_path.as_os_str().to_owned();
+    path.push(".json");
+    PathBuf::from(path)
+}
@@ -0,0 +1,157 @@
+/// How to rewrite `$[name]` placeholders in the rendered SQL.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, serde::Serialize, serde::Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum PlaceholderStyle {
+    /// `$1`, `$2`, etc., as used by PostgreSQL. A name that appears more than once reuses the same
+    /// number.
+    #[value(alias = "postgresql")]
+    #[serde(alias = "postgresql")]
+    Postgres,
+    /// `?`, as used by MySQL and SQLite. A name that appears more than once must be bound once for
+    /// each appearance.
+    #[value(alias = "mysql", alias = "sqlite")]
+    #[serde(alias = "mysql", alias = "sqlite")]
+    Question,
+    /// `:name`
+    Colon,
+    /// `%(name)s`, as used by Python database drivers.
+    Pyformat,
+}
+
+/// The result of rewriting the placeholders in a query.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct CompiledQuery {
+    pub sql: String,
+    /// The parameter names, in the order that they should be bound. For [PlaceholderStyle::Question]
+    /// a name appears once for each time it is used in the query.
+    pub parameters: Vec<String>,
+}
+
+/// Rewrite the `$[name]` placeholders in `sql` into the given style. Placeholders inside string
+/// literals and comments are left alone.
+pub(crate) fn compile_placeholders(sql: &str, style: PlaceholderStyle) -> CompiledQuery {
+    rewrite_placeholders(sql, style, false, false)
+}
+
+/// Like [compile_placeholders], but pad each parameter with spaces to the length of the
+/// placeholder it replaces, so that the rest of the query keeps the same line and column
+/// positions. If `pyformat` is true, `%(name)s` placeholders which have already been compiled
+/// are rewritten too, since SQL parsers don't understand them.
+pub(crate) fn mask_placeholders(sql: &str, style: PlaceholderStyle, pyformat: bool) -> String {
+    rewrite_placeholders(sql, style, true, pyformat).sql
+}
+
+fn rewrite_placeholders(
+    sql: &str,
+    style: PlaceholderStyle,
+    pad: bool,
+    pyformat: bool,
+) -> CompiledQuery {
+    let mut output = String::with_capacity(sql.len());
+    let mut parameters: Vec<String> = Vec::new();
+
+    let mut rest = sql;
+    while !rest.is_empty() {
+        if rest.starts_with('\'') {
+            let len = quoted_len(rest);
+            output.push_str(&rest[..len]);
+            rest = &rest[len..];
+        } else if rest.starts_with("--") {
+            let len = rest.find('\n').unwrap_or(rest.len());
+            output.push_str(&rest[..len]);
+            rest = &rest[len..];
+        } else if rest.starts_with("/*") {
+            let len = rest[2..].find("*/").map(|i| i + 4).unwrap_or(rest.len());
+            output.push_str(&rest[..len]);
+            rest = &rest[len..];
+        } else if let Some((name, len)) =
+            placeholder(rest).or_else(|| pyformat.then(|| pyformat_placeholder(rest)).flatten())
+        {
+            let start = output.len();
+            match style {
+                PlaceholderStyle::Postgres => {
+                    let index = match parameters.iter().position(|p| p == name) {
+                        Some(i) => i,
+                        None => {
+                            parameters.push(name.to_string());
+                            parameters.len() - 1
+                        }
+                    };
+                    output.push_str(&format!("${}", index + 1));
+                }
+                PlaceholderStyle::Question => {
+                    parameters.push(name.to_string());
+                    output.push('?');
+                }
+                PlaceholderStyle::Colon | PlaceholderStyle::Pyformat => {
+                    if !parameters.iter().any(|p| p == name) {
+                        parameters.push(name.to_string());
+                    }
+
+                    if style == PlaceholderStyle::Colon {
+                        output.push_str(&format!(":{name}"));
+                    } else {
+                        output.push_str(&format!("%({name})s"));
+                    }
+                }
+            }
+            if pad {
+                let padding = len.saturating_sub(output.len() - start);
+                output.push_str(&" ".repeat(padding));
+            }
+            rest = &rest[len..];
+        } else {
+            let c = rest.chars().next().unwrap();
+            output.push(c);
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+
+    CompiledQuery {
+        sql: output,
+        parameters,
+    }
+}
+
+/// The names of the `$[name]` placeholders in `sql`, in the order they first appear.
+pub(crate) fn placeholder_names(sql: &str) -> Vec<String> {
+    // Named styles list each parameter once, which is what we want here.
+    compile_placeholders(sql, PlaceholderStyle::Colon).parameters
+}
+
+/// The length of the single-quoted string at the start of `s`, including the quotes.
+fn quoted_len(s: &str) -> usize {
+    let mut chars = s.char_indices().skip(1).peekable();
+    while let Some((i, c)) = chars.next() {
+        if c == '\'' {
+            // A doubled quote is an escaped quote inside the string.
+            if chars.peek().map(|(_, c)| *c == '\'').unwrap_or(false) {
+                chars.next();
+                continue;
+            }
+            return i + 1;
+        }
+    }
+
+    s.len()
+}
+
+/// If `s` starts with a `$[name]` placeholder, return the name and the length of the placeholder.
+fn placeholder(s: &str) -> Option<(&str, usize)> {
+    let inner = s.strip_prefix("$[")?;
+    let end = inner.find(']')?;
+    let name = &inner[..end];
+    let valid = !name.is_empty() && name.chars().all(|c| c.is_alphanumeric() || c == '_');
+    valid.then_some((name, end + 3))
+}
+
+/// If `s` starts with a `%(name)s` placeholder, return the name and the length of the placeholder.
+fn pyformat_placeholder(s: &str) -> Option<(&str, usize)> {
+    let inner = s.strip_prefix("%(")?;
+    let end = inner.find(")s")?;
+    let name = &inner[..end];
+    let valid = !name.is_empty() && name.chars().all(|c| c.is_alphanumeric() || c == '_');
+    valid.then_some((name, end + 4))
+}
@@ -0,0 +1,98 @@
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
+
+use error_stack::{Report, ResultExt};
+
+use crate::{
+    diagnostics::print_status, header_lines, manifest::manifest_path, output_path, Error, Options,
+    TemplateSet,
+};
+
+/// Find generated files which no longer have a template. Only files that start with the sqlweld
+/// header are considered, so that hand-written SQL files are never touched. The manifests of those
+/// files are included as well.
+pub(crate) fn find_stale_outputs(
+    options: &Options,
+    templates: &TemplateSet,
+) -> Result<Vec<PathBuf>, Report<Error>> {
+    let header = header_lines(options);
+    if header.is_empty() {
+        return Err(Report::new(Error::PruneWithoutHeader));
+    }
+    let header = format!("{header}\n");
+
+    let expected = templates
+        .normal_templates()
+        .map(|(path, _)| output_path(options, &templates.input_dir, path))
+        .collect::<Result<HashSet<_>, _>>()?;
+
+    let search_dir = options.output.as_deref().unwrap_or(&templates.input_dir);
+    let extension = format!(".{}", options.extension.as_deref().unwrap_or("sql"));
+
+    let mut stale = ignore::WalkBuilder::new(search_dir)
+        .hidden(!options.check_ignored_dirs)
+        .follow_links(false)
+        .build()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().map(|f| f.is_file()).unwrap_or(false))
+        .map(|entry| entry.into_path())
+        .filter(|path| {
+            path.file_name()
+                .and_then(|f| f.to_str())
+                .map(|f| f.ends_with(&extension))
+                .unwrap_or(false)
+        })
+        .filter(|path| !expected.contains(path))
+        .filter(|path| has_header(path, &header))
+        .collect::<Vec<_>>();
+
+    let manifests = stale
+        .iter()
+        .map(|path| manifest_path(path))
+        .filter(|path| path.is_file())
+        .collect::<Vec<_>>();
+    stale.extend(manifests);
+
+    stale.sort();
+    Ok(stale)
+}
+
+fn has_header(path: &Path, header: &str) -> bool {
+    std::fs::read_to_string(path)
+        .map(|contents| contents.starts_with(header))
+        .unwrap_or(false)
+}
+
+/// Remove the generated files which no longer have a template, or just list them if
+/// [Options::dry_run] is set. Returns the stale files.
+pub(crate) fn prune(
+    options: &Options,
+    templates: &TemplateSet,
+) -> Result<Vec<PathBuf>, Report<Error>> {
+    let stale = find_stale_outputs(options, templates)?;
+
+    for path in &stale {
+        if options.dry_run {
+            print_status(
+                options.message_format,
+                format!("Would remove {}", path.display()),
+            );
+            continue;
+        }
+
+        if options.verbose >= 1 {
+            print_status(
+                options.message_format,
+                format!("Removing {}", path.display()),
+            );
+        }
+
+        std::fs::remove_file(path)
+            .change_context(Error::Prune)
+            .attach_printable_lazy(|| path.display().to_string())?;
+    }
+
+    Ok(stale)
+}
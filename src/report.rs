@@ -0,0 +1,148 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use crate::Diagnostic;
+
+/// A summary of what [build](crate::build) did.
+#[derive(Debug, Clone, Default)]
+pub struct BuildReport {
+    /// Every template file that was found, including partials and macro files.
+    pub templates: Vec<PathBuf>,
+    /// The partials and macro files, keyed by the name they can be imported as.
+    pub partials: BTreeMap<String, PathBuf>,
+    /// The templates that were rendered, sorted by template path.
+    pub rendered: Vec<TemplateReport>,
+    /// The Rust module, if [Options::rust_module](crate::Options) is set.
+    pub rust_module: Option<OutputFile>,
+    /// The dependency file, if [Options::depfile](crate::Options) is set. This is not written in
+    /// check mode.
+    pub depfile: Option<OutputFile>,
+    /// Generated files that were removed, or would have been removed in a dry run, because their
+    /// templates no longer exist.
+    pub pruned: Vec<PathBuf>,
+    /// Problems that did not stop the build.
+    pub warnings: Vec<Diagnostic>,
+    /// How long the whole build took.
+    pub elapsed: Duration,
+}
+
+impl BuildReport {
+    /// Iterate over every output file, along with what happened to it.
+    pub fn outputs(&self) -> impl Iterator<Item = &OutputFile> {
+        self.rendered.iter().flat_map(|t| t.outputs.iter())
+    }
+
+    /// The output files that were written.
+    pub fn written(&self) -> impl Iterator<Item = &Path> {
+        self.outputs()
+            .filter(|o| o.status == OutputStatus::Written)
+            .map(|o| o.path.as_path())
+    }
+}
+
+/// The result of rendering a single template.
+#[derive(Debug, Clone)]
+pub struct TemplateReport {
+    pub template: PathBuf,
+    /// The name of the query, which is the template path without the `.sql.tera` suffix.
+    pub name: String,
+    /// The files that the template was rendered from: the template itself, the templates,
+    /// partials, and macro files that it uses, and the context and configuration files.
+    pub inputs: Vec<PathBuf>,
+    /// The parameter names, in the order that they should be bound.
+    pub parameters: Vec<String>,
+    /// The contents of the generated SQL file.
+    pub(crate) sql: String,
+    /// The files generated from the template. This is the SQL file, and the manifest if
+    /// manifests are enabled.
+    pub outputs: Vec<OutputFile>,
+    /// How long it took to render the template and process its placeholders.
+    pub render_time: Duration,
+    /// How long the formatter took, if one was used. In batch mode this is the time for the whole
+    /// batch.
+    pub format_time: Option<Duration>,
+}
+
+/// A query rendered by [render_query](crate::render_query).
+#[derive(Debug, Clone)]
+pub struct RenderedQuery {
+    /// The name of the query, which is the template path without the `.sql.tera` suffix.
+    pub name: String,
+    /// The contents that the generated SQL file would have.
+    pub sql: String,
+    /// The parameter names, in the order that they should be bound.
+    pub parameters: Vec<String>,
+    /// The files that the query was rendered from: the template, the partials and macro files it
+    /// uses, and the context and configuration files.
+    pub files: Vec<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutputFile {
+    pub path: PathBuf,
+    pub status: OutputStatus,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputStatus {
+    /// The file was written.
+    Written,
+    /// The file already had the correct contents, so it was not written.
+    Unchanged,
+    /// In check mode, the file is missing or has different contents.
+    OutOfDate,
+}
+
+/// The results of [run_tests](crate::run_tests).
+#[derive(Debug, Clone, Default)]
+pub struct TestReport {
+    /// Every case that was run, sorted by template path and then in the order they appear in the
+    /// test file.
+    pub cases: Vec<TestCaseReport>,
+    /// How long the tests took.
+    pub elapsed: Duration,
+}
+
+impl TestReport {
+    /// The number of cases that passed.
+    pub fn passed(&self) -> usize {
+        self.cases.iter().filter(|case| case.passed()).count()
+    }
+
+    /// The number of cases that failed.
+    pub fn failed(&self) -> usize {
+        self.cases.len() - self.passed()
+    }
+}
+
+impl std::fmt::Display for TestReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for case in &self.cases {
+            let status = if case.passed() { "ok" } else { "FAILED" };
+            writeln!(f, "{} {}: {status}", case.template.display(), case.name)?;
+        }
+
+        write!(f, "{} passed, {} failed", self.passed(), self.failed())
+    }
+}
+
+/// The result of a single case from a template's test file.
+#[derive(Debug, Clone)]
+pub struct TestCaseReport {
+    /// The template that was rendered.
+    pub template: PathBuf,
+    /// The file that the case is defined in.
+    pub test_file: PathBuf,
+    pub name: String,
+    /// A description of each assertion that failed. This is empty if the case passed.
+    pub failures: Vec<String>,
+}
+
+impl TestCaseReport {
+    pub fn passed(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
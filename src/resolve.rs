@@ -0,0 +1,177 @@
+use std::{
+    collections::HashMap,
+    path::{Component, Path},
+};
+
+use error_stack::Report;
+use itertools::Itertools;
+use tera::ast::Node;
+
+use crate::{Error, TemplateSet};
+
+/// The name of a partial or macro file: its path relative to the input directory, without the
+/// suffix and always using `/` as the separator.
+pub(crate) fn partial_name(relative_path: &Path, suffix: &str) -> String {
+    let name = relative_path
+        .components()
+        .filter_map(|c| match c {
+            Component::Normal(part) => Some(part.to_string_lossy()),
+            _ => None,
+        })
+        .join("/");
+    name.strip_suffix(suffix).unwrap_or(&name).to_string()
+}
+
+impl TemplateSet {
+    /// The directory of a parsed template relative to the input directory, or to the partials
+    /// directory it came from, using `/` as the separator. Templates that don't come from a file
+    /// are treated as being at the top.
+    fn template_dir(&self, template: &tera::Template) -> String {
+        let Some(path) = template.path.as_deref().map(Path::new) else {
+            return String::new();
+        };
+
+        let relative = path.strip_prefix(self.root(path).1).unwrap_or(path);
+        partial_name(relative.parent().unwrap_or(Path::new("")), "")
+    }
+
+    /// Find the template that `reference` refers to from a template in `dir`.
+    ///
+    /// The reference is first looked up relative to `dir`, then relative to each of its parents
+    /// up to the input directory. This lets a bare name like `perm_check` find the nearest
+    /// partial with that name, and a path like `auth/perm_check` find a partial in a
+    /// subdirectory. If that fails, a partial anywhere in the tree with that name is used, as long
+    /// as there is only one.
+    fn resolve(&self, dir: &str, reference: &str) -> Result<String, Report<Error>> {
+        let mut current = Some(dir);
+        while let Some(dir) = current {
+            let candidate = if dir.is_empty() {
+                reference.to_string()
+            } else {
+                format!("{dir}/{reference}")
+            };
+
+            if self.parsed.contains_key(&candidate) {
+                return Ok(candidate);
+            }
+
+            current = match dir.rsplit_once('/') {
+                Some((parent, _)) => Some(parent),
+                None if !dir.is_empty() => Some(""),
+                None => None,
+            };
+        }
+
+        let suffix = format!("/{reference}");
+        let matches = self
+            .partials
+            .iter()
+            .filter(|(name, _)| name.ends_with(&suffix))
+            .sorted()
+            .collect::<Vec<_>>();
+
+        match matches.as_slice() {
+            [] => Ok(reference.to_string()),
+            [(name, _)] => Ok(name.to_string()),
+            _ => {
+                let mut error = Report::new(Error::DuplicatePartial).attach_printable(format!(
+                    "`{reference}` in `{dir}` could refer to more than one file. \
+                    Use the path of the one you want instead."
+                ));
+                for (_, path) in matches {
+                    error = error.attach_printable(path.display().to_string());
+                }
+                Err(error)
+            }
+        }
+    }
+
+    /// Resolve the references between the parsed templates, and make the results available
+    /// for rendering.
+    ///
+    /// This needs to run again whenever a template is added or removed, since that can change
+    /// what the references in other templates point to.
+    ///
+    /// The AST and [tera::Tera::templates] are hidden from Tera's documentation and not covered by
+    /// semver, which is why Cargo.toml only allows 1.19.x versions of Tera.
+    fn link_templates(&self) -> Result<HashMap<String, tera::Template>, Report<Error>> {
+        self.parsed
+            .values()
+            .map(|template| {
+                let dir = self.template_dir(template);
+                let mut template = template.clone();
+                let resolve = |reference: &mut String| -> Result<(), Report<Error>> {
+                    *reference = self.resolve(&dir, reference)?;
+                    Ok(())
+                };
+
+                if let Some(parent) = &mut template.parent {
+                    resolve(parent)?;
+                }
+                for (file, _) in &mut template.imported_macro_files {
+                    resolve(file)?;
+                }
+                // Blocks and macros are rendered from their own copies of the AST.
+                resolve_nodes(&mut template.ast, &resolve)?;
+                for block in template.blocks.values_mut() {
+                    resolve_nodes(&mut block.body, &resolve)?;
+                }
+                for definition in template.macros.values_mut() {
+                    resolve_nodes(&mut definition.body, &resolve)?;
+                }
+
+                Ok((template.name.clone(), template))
+            })
+            .collect()
+    }
+
+    /// Resolve references between templates and rebuild the inheritance chains.
+    pub(crate) fn link(&mut self) -> Result<(), Report<Error>> {
+        self.tera.templates = self.link_templates()?;
+
+        let result = self
+            .tera
+            .build_inheritance_chains()
+            .and_then(|_| self.tera.check_macro_files());
+        result.map_err(|e| self.load_error(e))
+    }
+}
+
+/// Rewrite the template names in `extends`, `import`, and `include` tags.
+fn resolve_nodes(
+    nodes: &mut [Node],
+    resolve: &impl Fn(&mut String) -> Result<(), Report<Error>>,
+) -> Result<(), Report<Error>> {
+    for node in nodes {
+        match node {
+            Node::Extends(_, name) | Node::ImportMacro(_, name, _) => resolve(name)?,
+            Node::Include(_, names, _) => {
+                for name in names {
+                    resolve(name)?;
+                }
+            }
+            Node::MacroDefinition(_, definition, _) => {
+                resolve_nodes(&mut definition.body, resolve)?
+            }
+            Node::FilterSection(_, section, _) => resolve_nodes(&mut section.body, resolve)?,
+            Node::Block(_, block, _) => resolve_nodes(&mut block.body, resolve)?,
+            Node::Forloop(_, forloop, _) => {
+                resolve_nodes(&mut forloop.body, resolve)?;
+                if let Some(body) = &mut forloop.empty_body {
+                    resolve_nodes(body, resolve)?;
+                }
+            }
+            Node::If(condition, _) => {
+                for (_, _, body) in &mut condition.conditions {
+                    resolve_nodes(body, resolve)?;
+                }
+                if let Some((_, body)) = &mut condition.otherwise {
+                    resolve_nodes(body, resolve)?;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,164 @@
+use std::collections::BTreeMap;
+
+use error_stack::{Report, ResultExt};
+use itertools::Itertools;
+
+use crate::{header_comment, sync_file, Error, Options, OutputFile, TemplateReport};
+
+/// The constants that list every query.
+const LOOKUP_TABLES: &[&str] = &["QUERIES", "QUERY_PARAMETERS"];
+
+/// The name of the constant that holds a query, such as `TEAM_A_GET_USERS` for `team_a/get_users`.
+fn const_name(query_name: &str) -> String {
+    let name = query_name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>();
+
+    if name.starts_with(|c: char| c.is_ascii_digit()) {
+        format!("_{name}")
+    } else {
+        name
+    }
+}
+
+/// Quote `s` as a raw string literal, using as many `#` as needed.
+fn raw_string(s: &str) -> String {
+    let mut hashes = "#".to_string();
+    while s.contains(&format!("\"{hashes}")) {
+        hashes.push('#');
+    }
+
+    format!("r{hashes}\"{s}\"{hashes}")
+}
+
+fn string_list(items: &[String]) -> String {
+    format!(
+        "&[{}]",
+        items.iter().map(|item| format!("{item:?}")).join(", ")
+    )
+}
+
+/// Generate Rust source with a constant for each query, along with its parameters and lookup
+/// tables for all the queries.
+pub(crate) fn rust_module<'a>(
+    options: &Options,
+    rendered: impl IntoIterator<Item = &'a TemplateReport>,
+) -> Result<String, Report<Error>> {
+    // Every constant in the module, mapped to the query that it belongs to, or `None` for the
+    // lookup tables.
+    let mut constants: BTreeMap<String, Option<&TemplateReport>> = LOOKUP_TABLES
+        .iter()
+        .map(|name| (name.to_string(), None))
+        .collect();
+    let mut queries = BTreeMap::new();
+    for template in rendered {
+        let const_name = const_name(&template.name);
+        for name in [const_name.clone(), format!("{const_name}_PARAMETERS")] {
+            match constants.insert(name.clone(), Some(template)) {
+                None => {}
+                Some(Some(existing)) => {
+                    return Err(Error::RustModule)
+                        .attach_printable(format!(
+                            "`{}` and `{}` would both use the constant name {name}",
+                            existing.name, template.name
+                        ))
+                        .attach_printable(existing.template.display().to_string())
+                        .attach_printable(template.template.display().to_string());
+                }
+                Some(None) => {
+                    return Err(Error::RustModule)
+                        .attach_printable(format!(
+                            "`{}` would use the constant name {name}, which is reserved for the \
+                            list of queries",
+                            template.name
+                        ))
+                        .attach_printable(template.template.display().to_string());
+                }
+            }
+        }
+
+        queries.insert(const_name, template);
+    }
+
+    let mut output = header_comment(options, "//");
+    if !output.is_empty() {
+        output.push('\n');
+    }
+
+    for (const_name, template) in &queries {
+        output.push_str(&format!(
+            "\n/// The `{}` query.\npub const {const_name}: &str = {};\n",
+            template.name,
+            raw_string(&template.sql)
+        ));
+        output.push_str(&format!(
+            "/// The parameters of [{const_name}], in the order they should be bound.\n\
+            pub const {const_name}_PARAMETERS: &[&str] = {};\n",
+            string_list(&template.parameters)
+        ));
+    }
+
+    let by_name = queries
+        .iter()
+        .sorted_by(|a, b| a.1.name.cmp(&b.1.name))
+        .collect::<Vec<_>>();
+
+    output.push_str("\n/// Every query, as pairs of name and SQL, sorted by name.\n");
+    output.push_str("pub const QUERIES: &[(&str, &str)] = &[\n");
+    for (const_name, template) in &by_name {
+        output.push_str(&format!("    ({:?}, {const_name}),\n", template.name));
+    }
+    output.push_str("];\n");
+
+    output.push_str("\n/// The parameters of every query, sorted by query name.\n");
+    output.push_str("pub const QUERY_PARAMETERS: &[(&str, &[&str])] = &[\n");
+    for (const_name, template) in &by_name {
+        output.push_str(&format!(
+            "    ({:?}, {const_name}_PARAMETERS),\n",
+            template.name
+        ));
+    }
+    output.push_str("];\n");
+
+    output.push_str(
+        r#"
+/// Look up a query's SQL by its name.
+pub fn query(name: &str) -> Option<&'static str> {
+    QUERIES
+        .binary_search_by(|(n, _)| (*n).cmp(name))
+        .ok()
+        .map(|i| QUERIES[i].1)
+}
+
+/// Look up a query's parameters by its name.
+pub fn query_parameters(name: &str) -> Option<&'static [&'static str]> {
+    QUERY_PARAMETERS
+        .binary_search_by(|(n, _)| (*n).cmp(name))
+        .ok()
+        .map(|i| QUERY_PARAMETERS[i].1)
+}
+"#,
+    );
+
+    Ok(output)
+}
+
+/// Write the Rust module to [Options::rust_module], if it is set.
+pub(crate) fn write_rust_module<'a>(
+    options: &Options,
+    rendered: impl IntoIterator<Item = &'a TemplateReport>,
+) -> Result<Option<OutputFile>, Report<Error>> {
+    let Some(path) = options.rust_module.as_ref() else {
+        return Ok(None);
+    };
+
+    let module = rust_module(options, rendered)?;
+    sync_file(options, path.clone(), &module).map(Some)
+}
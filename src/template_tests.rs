@@ -0,0 +1,165 @@
+use std::path::{Path, PathBuf};
+
+use error_stack::{Report, ResultExt};
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::{context, diagnostics, Error, TemplateSet, TestCaseReport};
+
+/// Tests for a template are in a file with this added to the template's file name, such as
+/// `get_some_objects.sql.tera.test.toml`.
+pub(crate) const TEST_FILE_SUFFIX: &str = ".test.toml";
+
+/// The contents of a test file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct TestFile {
+    /// Context used for every case in the file.
+    context: Option<Value>,
+    case: Vec<TestCase>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct TestCase {
+    name: Option<String>,
+    /// Context for this case, merged on top of the file's context.
+    context: Option<Value>,
+    /// The complete rendered output.
+    expected: Option<String>,
+    contains: Vec<String>,
+    not_contains: Vec<String>,
+    /// Regular expressions that must match somewhere in the output.
+    matches: Vec<String>,
+}
+
+/// The path of the test file for the template at `path`.
+pub(crate) fn test_file_path(path: &Path) -> PathBuf {
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(TEST_FILE_SUFFIX);
+    path.with_file_name(file_name)
+}
+
+/// Run the cases in `test_file` against the template `name`. `base_context` is the context that
+/// [build](crate::build) would use, which each case's context is merged into.
+pub(crate) fn run_test_file(
+    templates: &TemplateSet,
+    base_context: &Value,
+    path: &Path,
+    name: &str,
+    test_file: &Path,
+) -> Result<Vec<TestCaseReport>, Report<Error>> {
+    let contents = std::fs::read_to_string(test_file)
+        .change_context(Error::ReadTest)
+        .attach_printable_lazy(|| test_file.display().to_string())?;
+    let file: TestFile = toml::from_str(&contents)
+        .change_context(Error::ReadTest)
+        .attach_printable_lazy(|| test_file.display().to_string())?;
+
+    let mut file_context = base_context.clone();
+    if let Some(context) = file.context {
+        context::merge(&mut file_context, context, "")
+            .attach_printable_lazy(|| test_file.display().to_string())?;
+    }
+
+    file.case
+        .into_iter()
+        .enumerate()
+        .map(|(i, case)| {
+            let case_name = case
+                .name
+                .clone()
+                .unwrap_or_else(|| format!("case {}", i + 1));
+            let failures = run_case(templates, &file_context, name, case)
+                .attach_printable_lazy(|| format!("In `{case_name}`"))
+                .attach_printable_lazy(|| test_file.display().to_string())?;
+
+            Ok(TestCaseReport {
+                template: path.to_path_buf(),
+                test_file: test_file.to_path_buf(),
+                name: case_name,
+                failures,
+            })
+        })
+        .collect()
+}
+
+/// Render the template for a single case, and return a description of each assertion that
+/// failed.
+fn run_case(
+    templates: &TemplateSet,
+    file_context: &Value,
+    name: &str,
+    case: TestCase,
+) -> Result<Vec<String>, Report<Error>> {
+    let mut context = file_context.clone();
+    if let Some(case_context) = case.context {
+        context::merge(&mut context, case_context, "")?;
+    }
+    let context = tera::Context::from_value(context)
+        .change_context(Error::ReadTest)
+        .attach_printable("The context must be a table")?;
+
+    let output = match templates.tera.render(name, &context) {
+        Ok(output) => output,
+        Err(e) => {
+            let diagnostic = diagnostics::render_diagnostic(templates, name, &e);
+            return Ok(vec![format!("Failed to render: {diagnostic}")]);
+        }
+    };
+
+    let mut failures = Vec::new();
+    if let Some(expected) = case.expected {
+        let expected = normalize(&expected);
+        let actual = normalize(&output);
+        if expected != actual {
+            let diff = similar::TextDiff::from_lines(&expected, &actual)
+                .unified_diff()
+                .header("expected", "actual")
+                .to_string();
+            failures.push(format!("The output is different than expected:\n{diff}"));
+        }
+    }
+
+    for text in &case.contains {
+        if !output.contains(text.as_str()) {
+            failures.push(format!("The output does not contain {text:?}"));
+        }
+    }
+
+    for text in &case.not_contains {
+        if output.contains(text.as_str()) {
+            failures.push(format!("The output contains {text:?}"));
+        }
+    }
+
+    for pattern in &case.matches {
+        let regex = regex::Regex::new(pattern)
+            .change_context(Error::ReadTest)
+            .attach_printable_lazy(|| format!("Invalid regular expression {pattern:?}"))?;
+        if !regex.is_match(&output) {
+            failures.push(format!("The output does not match /{pattern}/"));
+        }
+    }
+
+    Ok(failures)
+}
+
+/// Remove trailing whitespace from each line, and blank lines at the start and end, so that
+/// expected output doesn't need to match the whitespace left behind by template tags.
+fn normalize(s: &str) -> String {
+    let lines = s.lines().map(|line| line.trim_end()).collect::<Vec<_>>();
+    let start = lines
+        .iter()
+        .position(|line| !line.is_empty())
+        .unwrap_or(lines.len());
+    let end = lines
+        .iter()
+        .rposition(|line| !line.is_empty())
+        .map(|i| i + 1)
+        .unwrap_or(start);
+
+    let mut normalized = lines[start..end].join("\n");
+    normalized.push('\n');
+    normalized
+}
@@ -1,6 +1,13 @@
 use tempfile::TempDir;
 
-use super::{build, Error, Options};
+use super::{
+    build, clean,
+    format::{Format, KeywordCase},
+    formatter::FormatterMode,
+    placeholders::{compile_placeholders, PlaceholderStyle},
+    report_diagnostics, run_tests, sarif_log, Diagnostic, Error, Options, OutputStatus, Severity,
+    Span, SqlDialect, StaleFiles, TestReport,
+};
 
 const UPDATE_SOME_OBJECTS: &'static str = include_str!("../test_data/update_some_objects.sql.tera");
 const GET_SOME_OBJECTS: &'static str = include_str!("../test_data/get_some_objects.sql.tera");
@@ -97,6 +104,152 @@ fn separate_output() {
     assert!(std::fs::File::open(output.join("perm_check.sql")).is_err());
 }
 
+#[test]
+fn options_builder() {
+    let dir = create_input();
+    let path = dir.path().to_owned();
+    let output = dir.path().join("output");
+
+    let mut context = tera::Context::new();
+    context.insert("table", "builder_table");
+    std::fs::write(
+        path.join("builder_test.sql.tera"),
+        "SELECT * FROM {{ table }} WHERE id = {{ id }}",
+    )
+    .unwrap();
+
+    let options = Options::builder()
+        .input(&path)
+        .output(&output)
+        .header("")
+        .extension("gen.sql")
+        .context(context)
+        .var("id", "5")
+        .build();
+
+    build(options).unwrap();
+
+    assert_eq!(
+        std::fs::read_to_string(output.join("builder_test.gen.sql")).unwrap(),
+        "SELECT * FROM builder_table WHERE id = 5"
+    );
+    assert_eq!(
+        std::fs::read_to_string(output.join("get_some_objects.gen.sql")).unwrap(),
+        strip_header(EXPECTED_GET_SOME_OBJECTS)
+    );
+}
+
+#[test]
+fn build_report() {
+    let dir = create_input();
+    let path = dir.path().to_owned();
+    std::fs::write(path.join("unused.macros.sql.tera"), "").unwrap();
+
+    let report = build(Options {
+        input: Some(path.clone()),
+        manifest: true,
+        ..Default::default()
+    })
+    .unwrap();
+
+    assert_eq!(
+        report.templates,
+        vec![
+            path.join("get_some_objects.sql.tera"),
+            path.join("perm_check.partial.sql.tera"),
+            path.join("unused.macros.sql.tera"),
+            path.join("update_some_objects.sql.tera"),
+        ]
+    );
+    assert_eq!(
+        report.partials.keys().collect::<Vec<_>>(),
+        vec!["perm_check", "unused"]
+    );
+    assert_eq!(
+        report
+            .rendered
+            .iter()
+            .map(|t| t.template.clone())
+            .collect::<Vec<_>>(),
+        vec![
+            path.join("get_some_objects.sql.tera"),
+            path.join("update_some_objects.sql.tera"),
+        ]
+    );
+    assert!(report.rendered.iter().all(|t| t.format_time.is_none()));
+    assert_eq!(report.written().count(), 4);
+    assert_eq!(
+        report
+            .warnings
+            .iter()
+            .map(|w| (w.code, w.message.clone()))
+            .collect::<Vec<_>>(),
+        vec![(
+            "unused-partial",
+            format!(
+                "{} is not used by any template",
+                path.join("unused.macros.sql.tera").display()
+            )
+        )]
+    );
+
+    std::fs::write(path.join("update_some_objects.sql"), "some old content").unwrap();
+    let report = build(Options {
+        input: Some(path.clone()),
+        ..Default::default()
+    })
+    .unwrap();
+
+    let statuses = report
+        .outputs()
+        .map(|o| (o.path.clone(), o.status))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        statuses,
+        vec![
+            (path.join("get_some_objects.sql"), OutputStatus::Unchanged),
+            (path.join("update_some_objects.sql"), OutputStatus::Written),
+        ]
+    );
+}
+
+#[test]
+fn separate_output_keeps_directory_structure() {
+    let dir = create_input();
+    let path = dir.path().to_owned();
+
+    std::fs::create_dir_all(path.join("in_subdir")).unwrap();
+    std::fs::write(path.join("root.partial.sql.tera"), ROOT_PARTIAL).unwrap();
+    std::fs::write(path.join("uses_root.sql.tera"), USES_ROOT_PARTIAL).unwrap();
+    std::fs::write(
+        path.join("in_subdir").join("uses_root.sql.tera"),
+        USES_ROOT_PARTIAL.replace("id = 1", "id = 2"),
+    )
+    .unwrap();
+
+    let output = dir.path().join("output");
+
+    build(Options {
+        input: Some(path.clone()),
+        output: Some(output.clone()),
+        ..Default::default()
+    })
+    .unwrap();
+
+    assert_eq!(
+        std::fs::read_to_string(output.join("uses_root.sql")).unwrap(),
+        apply_header(HEADER, EXPECTED_USES_ROOT_PARTIAL)
+    );
+    assert_eq!(
+        std::fs::read_to_string(output.join("in_subdir").join("uses_root.sql")).unwrap(),
+        apply_header(HEADER, EXPECTED_USES_ROOT_PARTIAL).replace("id = 1", "id = 2")
+    );
+    assert_eq!(
+        std::fs::read_to_string(output.join("update_some_objects.sql")).unwrap(),
+        apply_header(HEADER, EXPECTED_UPDATE_SOME_OBJECTS)
+    );
+}
+
 #[test]
 fn custom_header() {
     let dir = create_input();
@@ -214,7 +367,8 @@ fn duplicate_partials() {
     std::fs::create_dir(&dir2).unwrap();
 
     std::fs::write(dir1.join("dup.partial.sql.tera"), "abc").unwrap();
-    std::fs::write(dir2.join("dup.macros.sql.tera"), "def").unwrap();
+    std::fs::write(dir1.join("dup.macros.sql.tera"), "def").unwrap();
+    std::fs::write(dir2.join("dup.partial.sql.tera"), "ghi").unwrap();
 
     let err = build(Options {
         input: Some(path.clone()),
@@ -228,121 +382,2140 @@ fn duplicate_partials() {
 }
 
 #[test]
-fn inheritance() {
-    let dir = create_input();
+fn partials_in_different_directories() {
+    let dir = tempfile::tempdir().unwrap();
     let path = dir.path().to_owned();
 
-    std::fs::write(path.join("root.partial.sql.tera"), ROOT_PARTIAL).unwrap();
-    std::fs::write(path.join("uses_root.sql.tera"), USES_ROOT_PARTIAL).unwrap();
+    std::fs::create_dir_all(path.join("auth")).unwrap();
+    std::fs::create_dir_all(path.join("team_a/reports")).unwrap();
+    std::fs::create_dir_all(path.join("team_b")).unwrap();
 
-    build(Options {
+    std::fs::write(path.join("table.partial.sql.tera"), "root_table").unwrap();
+    std::fs::write(path.join("team_a/table.partial.sql.tera"), "a_table").unwrap();
+    std::fs::write(path.join("team_b/table.partial.sql.tera"), "b_table").unwrap();
+    std::fs::write(
+        path.join("auth/perm_check.macros.sql.tera"),
+        "{% macro check() %}auth_check(){% endmacro check %}",
+    )
+    .unwrap();
+
+    // Bare names find the nearest partial, searching up through parent directories.
+    std::fs::write(
+        path.join("team_a/reports/q.sql.tera"),
+        "SELECT * FROM {% include \"table\" %}",
+    )
+    .unwrap();
+    std::fs::write(
+        path.join("team_b/q.sql.tera"),
+        "SELECT * FROM {% include \"table\" %}",
+    )
+    .unwrap();
+    std::fs::write(
+        path.join("q.sql.tera"),
+        "SELECT * FROM {% include \"table\" %}",
+    )
+    .unwrap();
+    // Paths are relative to the template's directory or any of its parents.
+    std::fs::write(
+        path.join("team_b/uses_paths.sql.tera"),
+        "{% import \"auth/perm_check\" as auth %}SELECT * FROM {% include \"team_a/table\" %} WHERE {{ auth::check() }}",
+    )
+    .unwrap();
+
+    let report = build(Options {
         input: Some(path.clone()),
+        header: Some(String::new()),
         ..Default::default()
     })
     .unwrap();
 
+    assert!(report.partials.contains_key("team_a/table"));
+    assert!(report.partials.contains_key("auth/perm_check"));
+
+    let read = |p: &str| std::fs::read_to_string(path.join(p)).unwrap();
+    assert_eq!(read("team_a/reports/q.sql"), "SELECT * FROM a_table");
+    assert_eq!(read("team_b/q.sql"), "SELECT * FROM b_table");
+    assert_eq!(read("q.sql"), "SELECT * FROM root_table");
     assert_eq!(
-        std::fs::read_to_string(path.join("uses_root.sql")).unwrap(),
-        apply_header(HEADER, EXPECTED_USES_ROOT_PARTIAL)
+        read("team_b/uses_paths.sql"),
+        "SELECT * FROM a_table WHERE auth_check()"
     );
 }
 
 #[test]
-fn inherit_from_parent_dir() {
-    let dir = create_input();
+fn ambiguous_partial_name() {
+    let dir = tempfile::tempdir().unwrap();
     let path = dir.path().to_owned();
 
-    std::fs::create_dir_all(path.join("in_subdir")).unwrap();
-    std::fs::write(path.join("root.partial.sql.tera"), ROOT_PARTIAL).unwrap();
+    std::fs::create_dir_all(path.join("team_a")).unwrap();
+    std::fs::create_dir_all(path.join("team_b")).unwrap();
+    std::fs::write(path.join("team_a/table.partial.sql.tera"), "a_table").unwrap();
+    std::fs::write(path.join("team_b/table.partial.sql.tera"), "b_table").unwrap();
     std::fs::write(
-        path.join("in_subdir").join("uses_root.sql.tera"),
-        USES_ROOT_PARTIAL,
+        path.join("q.sql.tera"),
+        "SELECT * FROM {% include \"table\" %}",
     )
     .unwrap();
 
-    build(Options {
+    let err = build(Options {
         input: Some(path.clone()),
         ..Default::default()
     })
-    .unwrap();
+    .expect_err("should fail");
 
-    assert_eq!(
-        std::fs::read_to_string(path.join("in_subdir").join("uses_root.sql")).unwrap(),
-        apply_header(HEADER, EXPECTED_USES_ROOT_PARTIAL)
-    );
+    assert!(matches!(err.current_context(), Error::DuplicatePartial));
+    let message = format!("{err:?}");
+    assert!(message.contains("team_a/table.partial.sql.tera"));
+    assert!(message.contains("team_b/table.partial.sql.tera"));
 }
 
 #[test]
-fn skip_write_if_not_changed() {
-    let dir = create_input();
+fn shared_partials_dirs() {
+    let dir = tempfile::tempdir().unwrap();
     let path = dir.path().to_owned();
+    let company = tempfile::tempdir().unwrap();
+    let team = tempfile::tempdir().unwrap();
 
+    std::fs::create_dir_all(company.path().join("tenancy")).unwrap();
     std::fs::write(
-        path.join("update_some_objects.sql"),
-        apply_header(HEADER, EXPECTED_UPDATE_SOME_OBJECTS),
+        company.path().join("tenancy/filter.macros.sql.tera"),
+        "{% macro tenant() %}tenant_id = $[tenant_id]{% endmacro tenant %}",
+    )
+    .unwrap();
+    std::fs::write(
+        company.path().join("soft_delete.partial.sql.tera"),
+        "company_deleted",
     )
     .unwrap();
+    std::fs::write(
+        company.path().join("table.partial.sql.tera"),
+        "company_table",
+    )
+    .unwrap();
+    // Normal templates in a partials directory are not rendered.
+    std::fs::write(company.path().join("not_rendered.sql.tera"), "SELECT 1").unwrap();
 
-    let current_mtime = std::fs::metadata(path.join("update_some_objects.sql"))
-        .unwrap()
-        .modified()
-        .unwrap();
+    std::fs::write(
+        team.path().join("soft_delete.partial.sql.tera"),
+        "team_deleted",
+    )
+    .unwrap();
+    std::fs::write(team.path().join("table.partial.sql.tera"), "team_table").unwrap();
 
-    std::thread::sleep(std::time::Duration::from_millis(1));
+    std::fs::write(path.join("table.partial.sql.tera"), "local_table").unwrap();
+    std::fs::write(
+        path.join("q.sql.tera"),
+        "{% import \"tenancy/filter\" as t %}SELECT * FROM {% include \"table\" %} WHERE {{ t::tenant() }} AND {% include \"soft_delete\" %}",
+    )
+    .unwrap();
 
-    build(Options {
-        input: Some(path.clone()),
-        ..Default::default()
-    })
+    let report = build(
+        Options::builder()
+            .input(&path)
+            .partials_dir(team.path())
+            .partials_dir(company.path())
+            .header("")
+            .build(),
+    )
     .unwrap();
 
-    let new_mtime = std::fs::metadata(path.join("update_some_objects.sql"))
-        .unwrap()
-        .modified()
-        .unwrap();
+    // Local partials shadow shared ones, and earlier directories shadow later ones.
+    assert_eq!(
+        std::fs::read_to_string(path.join("q.sql")).unwrap(),
+        "SELECT * FROM local_table WHERE tenant_id = $[tenant_id] AND team_deleted"
+    );
+    assert_eq!(
+        report.partials.get("table"),
+        Some(&path.join("table.partial.sql.tera"))
+    );
+    assert_eq!(
+        report.partials.get("soft_delete"),
+        Some(&team.path().join("soft_delete.partial.sql.tera"))
+    );
+    assert!(report.warnings.is_empty(), "{:?}", report.warnings);
+    assert!(!company.path().join("not_rendered.sql").exists());
+    assert!(!company.path().join("table.sql").exists());
+}
 
-    println!("current_mtime: {:?}", current_mtime);
-    println!("new_mtime: {:?}", new_mtime);
+#[test]
+fn partial_sources() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().to_owned();
+
+    std::fs::write(path.join("table.partial.sql.tera"), "local_table").unwrap();
+    std::fs::write(
+        path.join("q.sql.tera"),
+        "{% import \"auth/perm_check\" as auth %}SELECT * FROM {% include \"table\" %} WHERE {{ auth::check() }}",
+    )
+    .unwrap();
+
+    let mut options = Options::builder()
+        .input(&path)
+        .header("")
+        .partial_source(
+            "auth/perm_check.macros.sql.tera",
+            "{% macro check() %}{% include \"clause\" %}{% endmacro check %}",
+        )
+        .partial_source("auth/clause", "auth_check()")
+        .build();
+    options.add_partial_source("table", "embedded_table");
+
+    let report = build(options).unwrap();
 
     assert_eq!(
-        current_mtime, new_mtime,
-        "file should not have been changed"
+        std::fs::read_to_string(path.join("q.sql")).unwrap(),
+        "SELECT * FROM local_table WHERE auth_check()"
     );
+    assert!(report.partials.contains_key("auth/perm_check"));
+    assert!(report.partials.contains_key("auth/clause"));
     assert_eq!(
-        std::fs::read_to_string(path.join("update_some_objects.sql")).unwrap(),
-        apply_header(HEADER, EXPECTED_UPDATE_SOME_OBJECTS)
+        report.partials.get("table"),
+        Some(&path.join("table.partial.sql.tera"))
     );
+    assert!(report.warnings.is_empty(), "{:?}", report.warnings);
+}
+
+#[test]
+fn partial_source_errors() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().to_owned();
+    std::fs::write(path.join("q.sql.tera"), "SELECT {% include \"broken\" %}").unwrap();
+
+    let err = build(
+        Options::builder()
+            .input(&path)
+            .partial_source("broken", "{{ missing_value }}")
+            .build(),
+    )
+    .expect_err("should fail");
+
+    let diagnostic = diagnostic(&err);
+    let location = diagnostic.location.as_ref().unwrap();
     assert_eq!(
-        std::fs::read_to_string(path.join("get_some_objects.sql")).unwrap(),
-        apply_header(HEADER, EXPECTED_GET_SOME_OBJECTS)
+        location.path,
+        std::path::Path::new("<partial sources>/broken.partial.sql.tera")
     );
-
-    assert!(std::fs::File::open(path.join("other_template.sql")).is_err());
-    assert!(std::fs::File::open(path.join("perm_check.sql")).is_err());
+    assert_eq!(location.span.as_ref().unwrap().column, 4);
 }
 
 #[test]
-fn overwrite() {
+fn keep_going_reports_all_failures() {
     let dir = create_input();
     let path = dir.path().to_owned();
 
-    std::fs::write(path.join("update_some_objects.sql"), "some old content").unwrap();
+    std::fs::write(path.join("a_broken.sql.tera"), "{{ missing_a }}").unwrap();
+    std::fs::write(path.join("z_broken.sql.tera"), "{{ missing_z }}").unwrap();
 
-    build(Options {
+    let err = build(Options {
         input: Some(path.clone()),
+        keep_going: true,
         ..Default::default()
     })
-    .unwrap();
+    .expect_err("should fail");
 
-    assert_eq!(
-        std::fs::read_to_string(path.join("update_some_objects.sql")).unwrap(),
-        apply_header(HEADER, EXPECTED_UPDATE_SOME_OBJECTS)
-    );
+    assert!(matches!(err.current_context(), Error::TemplateFailures));
+
+    let failures = err
+        .frames()
+        .filter_map(|frame| frame.downcast_ref::<Error>())
+        .filter(|e| matches!(e, Error::Render))
+        .count();
+    assert_eq!(failures, 2);
+
+    let message = format!("{err:?}");
+    let a = message.find("a_broken.sql.tera").unwrap();
+    let z = message.find("z_broken.sql.tera").unwrap();
+    assert!(a < z, "failures should be ordered by path");
+
+    // The templates that worked were still written.
     assert_eq!(
         std::fs::read_to_string(path.join("get_some_objects.sql")).unwrap(),
         apply_header(HEADER, EXPECTED_GET_SOME_OBJECTS)
     );
+}
 
-    assert!(std::fs::File::open(path.join("other_template.sql")).is_err());
-    assert!(std::fs::File::open(path.join("perm_check.sql")).is_err());
+fn diagnostic(err: &error_stack::Report<Error>) -> &Diagnostic {
+    err.frames()
+        .find_map(|frame| frame.downcast_ref::<Diagnostic>())
+        .expect("error should have a diagnostic")
+}
+
+#[test]
+fn diagnostic_in_macro() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().to_owned();
+
+    std::fs::write(
+        path.join("checks.partial.sql.tera"),
+        "{% macro owner(table) %}\n{{ table }}.owner = {{ owner_id }}\n{% endmacro owner %}",
+    )
+    .unwrap();
+    std::fs::write(
+        path.join("query.sql.tera"),
+        "{% import \"checks\" as checks %}\nSELECT * FROM t\nWHERE {{ checks::owner(table=\"t\") }}",
+    )
+    .unwrap();
+
+    let err = build(Options {
+        input: Some(path.clone()),
+        ..Default::default()
+    })
+    .expect_err("should fail");
+
+    let diagnostic = diagnostic(&err);
+    assert!(diagnostic.message.contains("owner_id"));
+    let location = diagnostic.location.as_ref().unwrap();
+    assert_eq!(location.path, path.join("checks.partial.sql.tera"));
+    let span = location.span.unwrap();
+    assert_eq!((span.line, span.column, span.length), (2, 24, 8));
+    assert!(
+        diagnostic
+            .notes
+            .iter()
+            .any(|note| note.contains("checks::owner") && note.contains("query.sql.tera:3:10")),
+        "notes should point to the call site: {:?}",
+        diagnostic.notes
+    );
+}
+
+#[test]
+fn diagnostic_in_parent() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().to_owned();
+
+    std::fs::write(
+        path.join("root.partial.sql.tera"),
+        "SELECT *\nFROM {{ table_name }}\n{% block where %}{% endblock %}",
+    )
+    .unwrap();
+    std::fs::write(
+        path.join("child.sql.tera"),
+        "{% extends \"root\" %}{% block where %}WHERE true{% endblock %}",
+    )
+    .unwrap();
+
+    let err = build(Options {
+        input: Some(path.clone()),
+        ..Default::default()
+    })
+    .expect_err("should fail");
+
+    let diagnostic = diagnostic(&err);
+    let location = diagnostic.location.as_ref().unwrap();
+    assert_eq!(location.path, path.join("root.partial.sql.tera"));
+    let span = location.span.unwrap();
+    assert_eq!((span.line, span.column), (2, 9));
+    assert!(format!("{diagnostic}").contains("FROM {{ table_name }}"));
+}
+
+#[test]
+fn diagnostic_for_syntax_error() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().to_owned();
+
+    std::fs::write(path.join("bad.sql.tera"), "SELECT 1\nFROM {{ a b }}").unwrap();
+
+    let err = build(Options {
+        input: Some(path.clone()),
+        ..Default::default()
+    })
+    .expect_err("should fail");
+
+    let diagnostic = diagnostic(&err);
+    let location = diagnostic.location.as_ref().unwrap();
+    assert_eq!(location.path, path.join("bad.sql.tera"));
+    assert_eq!(location.span.unwrap().line, 2);
+}
+
+#[test]
+fn report_diagnostics_for_each_failure() {
+    let dir = create_input();
+    let path = dir.path().to_owned();
+
+    std::fs::write(path.join("a_broken.sql.tera"), "SELECT {{ missing_a }}").unwrap();
+    std::fs::write(path.join("z_broken.sql.tera"), "SELECT {{ missing_z }}").unwrap();
+
+    let err = build(Options {
+        input: Some(path.clone()),
+        keep_going: true,
+        ..Default::default()
+    })
+    .expect_err("should fail");
+
+    let diagnostics = report_diagnostics(&err);
+    assert_eq!(diagnostics.len(), 2);
+    assert!(diagnostics
+        .iter()
+        .all(|d| d.code == "render" && d.severity == Severity::Error));
+
+    let mut locations = diagnostics
+        .iter()
+        .map(|d| d.location.clone().unwrap())
+        .collect::<Vec<_>>();
+    locations.sort_by(|a, b| a.path.cmp(&b.path));
+    assert_eq!(locations[0].path, path.join("a_broken.sql.tera"));
+    assert_eq!(locations[1].path, path.join("z_broken.sql.tera"));
+    assert_eq!(locations[0].span.unwrap().column, 11);
+
+    let json = serde_json::to_value(&diagnostics[0]).unwrap();
+    assert_eq!(json["code"], "render");
+    assert_eq!(json["severity"], "error");
+    assert_eq!(json["location"]["span"]["column"], 11);
+
+    let sarif = sarif_log(&diagnostics);
+    let results = sarif["runs"][0]["results"].as_array().unwrap();
+    assert_eq!(results.len(), 2);
+    assert_eq!(results[0]["ruleId"], "render");
+    assert_eq!(results[0]["level"], "error");
+    assert_eq!(
+        results[0]["locations"][0]["physicalLocation"]["region"]["startLine"],
+        1
+    );
+    assert_eq!(
+        sarif["runs"][0]["tool"]["driver"]["rules"][0]["id"],
+        "render"
+    );
+}
+
+#[test]
+fn report_diagnostics_for_duplicate_partials() {
+    let dir = create_input();
+    let path = dir.path().to_owned();
+
+    std::fs::write(path.join("perm_check.macros.sql.tera"), PERM_CHECK).unwrap();
+
+    let err = build(Options {
+        input: Some(path.clone()),
+        ..Default::default()
+    })
+    .expect_err("should fail");
+
+    let diagnostics = report_diagnostics(&err);
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].code, "duplicate-partial");
+    let location = diagnostics[0].location.as_ref().unwrap();
+    // Either file can be found first.
+    assert!(location.path.starts_with(&path));
+    assert!(location.path.to_string_lossy().ends_with(".sql.tera"));
+    assert_eq!(diagnostics[0].notes.len(), 1);
+}
+
+#[test]
+fn inheritance() {
+    let dir = create_input();
+    let path = dir.path().to_owned();
+
+    std::fs::write(path.join("root.partial.sql.tera"), ROOT_PARTIAL).unwrap();
+    std::fs::write(path.join("uses_root.sql.tera"), USES_ROOT_PARTIAL).unwrap();
+
+    build(Options {
+        input: Some(path.clone()),
+        ..Default::default()
+    })
+    .unwrap();
+
+    assert_eq!(
+        std::fs::read_to_string(path.join("uses_root.sql")).unwrap(),
+        apply_header(HEADER, EXPECTED_USES_ROOT_PARTIAL)
+    );
+}
+
+#[test]
+fn inherit_from_parent_dir() {
+    let dir = create_input();
+    let path = dir.path().to_owned();
+
+    std::fs::create_dir_all(path.join("in_subdir")).unwrap();
+    std::fs::write(path.join("root.partial.sql.tera"), ROOT_PARTIAL).unwrap();
+    std::fs::write(
+        path.join("in_subdir").join("uses_root.sql.tera"),
+        USES_ROOT_PARTIAL,
+    )
+    .unwrap();
+
+    build(Options {
+        input: Some(path.clone()),
+        ..Default::default()
+    })
+    .unwrap();
+
+    assert_eq!(
+        std::fs::read_to_string(path.join("in_subdir").join("uses_root.sql")).unwrap(),
+        apply_header(HEADER, EXPECTED_USES_ROOT_PARTIAL)
+    );
+}
+
+#[test]
+fn skip_write_if_not_changed() {
+    let dir = create_input();
+    let path = dir.path().to_owned();
+
+    std::fs::write(
+        path.join("update_some_objects.sql"),
+        apply_header(HEADER, EXPECTED_UPDATE_SOME_OBJECTS),
+    )
+    .unwrap();
+
+    let current_mtime = std::fs::metadata(path.join("update_some_objects.sql"))
+        .unwrap()
+        .modified()
+        .unwrap();
+
+    std::thread::sleep(std::time::Duration::from_millis(1));
+
+    build(Options {
+        input: Some(path.clone()),
+        ..Default::default()
+    })
+    .unwrap();
+
+    let new_mtime = std::fs::metadata(path.join("update_some_objects.sql"))
+        .unwrap()
+        .modified()
+        .unwrap();
+
+    println!("current_mtime: {:?}", current_mtime);
+    println!("new_mtime: {:?}", new_mtime);
+
+    assert_eq!(
+        current_mtime, new_mtime,
+        "file should not have been changed"
+    );
+    assert_eq!(
+        std::fs::read_to_string(path.join("update_some_objects.sql")).unwrap(),
+        apply_header(HEADER, EXPECTED_UPDATE_SOME_OBJECTS)
+    );
+    assert_eq!(
+        std::fs::read_to_string(path.join("get_some_objects.sql")).unwrap(),
+        apply_header(HEADER, EXPECTED_GET_SOME_OBJECTS)
+    );
+
+    assert!(std::fs::File::open(path.join("other_template.sql")).is_err());
+    assert!(std::fs::File::open(path.join("perm_check.sql")).is_err());
+}
+
+#[test]
+fn overwrite() {
+    let dir = create_input();
+    let path = dir.path().to_owned();
+
+    std::fs::write(path.join("update_some_objects.sql"), "some old content").unwrap();
+
+    build(Options {
+        input: Some(path.clone()),
+        ..Default::default()
+    })
+    .unwrap();
+
+    assert_eq!(
+        std::fs::read_to_string(path.join("update_some_objects.sql")).unwrap(),
+        apply_header(HEADER, EXPECTED_UPDATE_SOME_OBJECTS)
+    );
+    assert_eq!(
+        std::fs::read_to_string(path.join("get_some_objects.sql")).unwrap(),
+        apply_header(HEADER, EXPECTED_GET_SOME_OBJECTS)
+    );
+
+    assert!(std::fs::File::open(path.join("other_template.sql")).is_err());
+    assert!(std::fs::File::open(path.join("perm_check.sql")).is_err());
+}
+
+#[test]
+fn check_up_to_date() {
+    let dir = create_input();
+    let path = dir.path().to_owned();
+
+    build(Options {
+        input: Some(path.clone()),
+        ..Default::default()
+    })
+    .unwrap();
+
+    build(Options {
+        input: Some(path.clone()),
+        check: true,
+        ..Default::default()
+    })
+    .unwrap();
+}
+
+#[test]
+fn check_reports_stale_files() {
+    let dir = create_input();
+    let path = dir.path().to_owned();
+
+    std::fs::write(path.join("update_some_objects.sql"), "some old content").unwrap();
+
+    let err = build(Options {
+        input: Some(path.clone()),
+        check: true,
+        ..Default::default()
+    })
+    .expect_err("should fail");
+
+    assert!(matches!(err.current_context(), Error::OutOfDate));
+    assert_eq!(
+        err.downcast_ref::<StaleFiles>().unwrap(),
+        &StaleFiles(vec![
+            path.join("get_some_objects.sql"),
+            path.join("update_some_objects.sql"),
+        ])
+    );
+
+    assert_eq!(
+        std::fs::read_to_string(path.join("update_some_objects.sql")).unwrap(),
+        "some old content"
+    );
+    assert!(std::fs::File::open(path.join("get_some_objects.sql")).is_err());
+}
+
+#[test]
+fn prune_stale_outputs() {
+    let dir = create_input();
+    let path = dir.path().to_owned();
+
+    build(Options {
+        input: Some(path.clone()),
+        ..Default::default()
+    })
+    .unwrap();
+
+    std::fs::remove_file(path.join("get_some_objects.sql.tera")).unwrap();
+    std::fs::write(path.join("hand_written.sql"), "SELECT 1").unwrap();
+
+    build(Options {
+        input: Some(path.clone()),
+        prune: true,
+        ..Default::default()
+    })
+    .unwrap();
+
+    assert!(std::fs::File::open(path.join("get_some_objects.sql")).is_err());
+    assert!(std::fs::File::open(path.join("update_some_objects.sql")).is_ok());
+    assert!(std::fs::File::open(path.join("hand_written.sql")).is_ok());
+}
+
+#[test]
+fn check_does_not_prune_without_templates() {
+    let dir = create_input();
+    let path = dir.path().to_owned();
+
+    build(Options {
+        input: Some(path.clone()),
+        ..Default::default()
+    })
+    .unwrap();
+
+    for entry in std::fs::read_dir(&path).unwrap() {
+        let entry = entry.unwrap().path();
+        if entry.to_string_lossy().ends_with(".tera") {
+            std::fs::remove_file(entry).unwrap();
+        }
+    }
+
+    build(Options {
+        input: Some(path.clone()),
+        check: true,
+        prune: true,
+        ..Default::default()
+    })
+    .unwrap();
+
+    assert!(std::fs::File::open(path.join("get_some_objects.sql")).is_ok());
+    assert!(std::fs::File::open(path.join("update_some_objects.sql")).is_ok());
+}
+
+#[test]
+fn clean_dry_run() {
+    let dir = create_input();
+    let path = dir.path().to_owned();
+    let output = path.join("output");
+
+    build(Options {
+        input: Some(path.clone()),
+        output: Some(output.clone()),
+        header: Some("custom header".to_string()),
+        ..Default::default()
+    })
+    .unwrap();
+
+    std::fs::remove_file(path.join("get_some_objects.sql.tera")).unwrap();
+
+    let stale = clean(Options {
+        input: Some(path.clone()),
+        output: Some(output.clone()),
+        header: Some("custom header".to_string()),
+        dry_run: true,
+        ..Default::default()
+    })
+    .unwrap();
+
+    assert_eq!(stale, vec![output.join("get_some_objects.sql")]);
+    assert!(std::fs::File::open(output.join("get_some_objects.sql")).is_ok());
+
+    let stale = clean(Options {
+        input: Some(path.clone()),
+        output: Some(output.clone()),
+        header: Some("custom header".to_string()),
+        ..Default::default()
+    })
+    .unwrap();
+
+    assert_eq!(stale, vec![output.join("get_some_objects.sql")]);
+    assert!(std::fs::File::open(output.join("get_some_objects.sql")).is_err());
+    assert!(std::fs::File::open(output.join("update_some_objects.sql")).is_ok());
+}
+
+#[test]
+fn prune_requires_header() {
+    let dir = create_input();
+    let path = dir.path().to_owned();
+
+    let err = clean(Options {
+        input: Some(path.clone()),
+        header: Some("".to_string()),
+        ..Default::default()
+    })
+    .expect_err("should fail");
+
+    assert!(matches!(err.current_context(), Error::PruneWithoutHeader));
+}
+
+#[test]
+fn placeholders_postgres() {
+    let dir = create_input();
+    let path = dir.path().to_owned();
+
+    build(Options {
+        input: Some(path.clone()),
+        placeholders: Some(PlaceholderStyle::Postgres),
+        ..Default::default()
+    })
+    .unwrap();
+
+    let expected = apply_header(
+        "-- Autogenerated by sqlweld\n-- Parameters: obj_id, team_id, user_id",
+        EXPECTED_GET_SOME_OBJECTS,
+    )
+    .replace("$[obj_id]", "$1")
+    .replace("$[team_id]", "$2")
+    .replace("$[user_id]", "$3");
+
+    assert_eq!(
+        std::fs::read_to_string(path.join("get_some_objects.sql")).unwrap(),
+        expected
+    );
+}
+
+#[test]
+fn placeholder_styles() {
+    let sql = "SELECT $[a], $[b], $[a]";
+
+    let compiled = compile_placeholders(sql, PlaceholderStyle::Postgres);
+    assert_eq!(compiled.sql, "SELECT $1, $2, $1");
+    assert_eq!(compiled.parameters, vec!["a", "b"]);
+
+    let compiled = compile_placeholders(sql, PlaceholderStyle::Question);
+    assert_eq!(compiled.sql, "SELECT ?, ?, ?");
+    assert_eq!(compiled.parameters, vec!["a", "b", "a"]);
+
+    let compiled = compile_placeholders(sql, PlaceholderStyle::Colon);
+    assert_eq!(compiled.sql, "SELECT :a, :b, :a");
+    assert_eq!(compiled.parameters, vec!["a", "b"]);
+
+    let compiled = compile_placeholders(sql, PlaceholderStyle::Pyformat);
+    assert_eq!(compiled.sql, "SELECT %(a)s, %(b)s, %(a)s");
+    assert_eq!(compiled.parameters, vec!["a", "b"]);
+}
+
+#[test]
+fn placeholders_skip_strings_and_comments() {
+    let sql = "SELECT '$[a]''$[b]', $[c] -- $[d]\n/* $[e] */ $[f] $[not valid]";
+    let compiled = compile_placeholders(sql, PlaceholderStyle::Postgres);
+    assert_eq!(
+        compiled.sql,
+        "SELECT '$[a]''$[b]', $1 -- $[d]\n/* $[e] */ $2 $[not valid]"
+    );
+    assert_eq!(compiled.parameters, vec!["c", "f"]);
+}
+
+#[test]
+fn validate_sql() {
+    let dir = create_input();
+    let path = dir.path().to_owned();
+
+    for dialect in [
+        SqlDialect::Postgres,
+        SqlDialect::Mysql,
+        SqlDialect::Sqlite,
+        SqlDialect::Generic,
+    ] {
+        build(Options::builder().input(&path).validate(dialect).build()).unwrap();
+    }
+
+    for style in [
+        PlaceholderStyle::Postgres,
+        PlaceholderStyle::Question,
+        PlaceholderStyle::Colon,
+        PlaceholderStyle::Pyformat,
+    ] {
+        for dialect in [
+            SqlDialect::Postgres,
+            SqlDialect::Mysql,
+            SqlDialect::Sqlite,
+            SqlDialect::Generic,
+        ] {
+            build(
+                Options::builder()
+                    .input(&path)
+                    .placeholders(style)
+                    .validate(dialect)
+                    .build(),
+            )
+            .unwrap_or_else(|e| panic!("{style:?} with {dialect:?}: {e:?}"));
+        }
+    }
+}
+
+#[test]
+fn validate_sql_error_in_partial() {
+    let dir = create_input();
+    let path = dir.path().to_owned();
+    std::fs::write(
+        path.join("perm_check.partial.sql.tera"),
+        PERM_CHECK.replace("FROM permissions", "FROM permissions p q"),
+    )
+    .unwrap();
+
+    let err = build(
+        Options::builder()
+            .input(&path)
+            .validate(SqlDialect::Postgres)
+            .build(),
+    )
+    .expect_err("should fail");
+
+    let diagnostics = report_diagnostics(&err);
+    assert_eq!(diagnostics.len(), 1);
+    let diagnostic = &diagnostics[0];
+    assert_eq!(diagnostic.code, "invalid-sql");
+    assert_eq!(diagnostic.message, "Expected ), found: q");
+
+    let location = diagnostic.location.as_ref().unwrap();
+    let output = std::fs::read_to_string(&location.path).unwrap_or_default();
+    assert!(location.path.ends_with("get_some_objects.sql"));
+    assert!(output.is_empty(), "invalid output should not be written");
+    assert_eq!(
+        location.span,
+        Some(Span {
+            line: 7,
+            column: 22,
+            length: 1
+        })
+    );
+    assert!(diagnostic
+        .notes
+        .iter()
+        .any(|note| note.ends_with("get_some_objects.sql.tera")));
+    assert!(diagnostic.notes.contains(&format!(
+        "from {}:4:22",
+        path.join("perm_check.partial.sql.tera").display()
+    )));
+}
+
+#[test]
+fn mask_placeholders_keeps_positions() {
+    use crate::placeholders::mask_placeholders;
+
+    assert_eq!(
+        mask_placeholders(
+            "a = $[abc] AND b = '$[x]'",
+            PlaceholderStyle::Postgres,
+            false
+        ),
+        "a = $1     AND b = '$[x]'"
+    );
+    assert_eq!(
+        mask_placeholders("a = $[abc], $[abc]", PlaceholderStyle::Question, false),
+        "a = ?     , ?     "
+    );
+    assert_eq!(
+        mask_placeholders(
+            "a = %(abc)s, $[b], '%(c)s'",
+            PlaceholderStyle::Postgres,
+            true
+        ),
+        "a = $1     , $2  , '%(c)s'"
+    );
+}
+
+#[test]
+fn manifest() {
+    let dir = create_input();
+    let path = dir.path().to_owned();
+
+    build(Options {
+        input: Some(path.clone()),
+        placeholders: Some(PlaceholderStyle::Question),
+        manifest: true,
+        ..Default::default()
+    })
+    .unwrap();
+
+    let manifest: serde_json::Value = serde_json::from_str(
+        &std::fs::read_to_string(path.join("get_some_objects.sql.json")).unwrap(),
+    )
+    .unwrap();
+
+    assert_eq!(
+        manifest,
+        serde_json::json!({
+            "name": "get_some_objects",
+            "template": "get_some_objects.sql.tera",
+            "placeholder_style": "question",
+            "parameters": ["obj_id", "team_id", "user_id", "team_id"],
+            "dependencies": ["perm_check"],
+        })
+    );
+
+    std::fs::remove_file(path.join("get_some_objects.sql.tera")).unwrap();
+    let stale = clean(Options {
+        input: Some(path.clone()),
+        ..Default::default()
+    })
+    .unwrap();
+
+    assert_eq!(
+        stale,
+        vec![
+            path.join("get_some_objects.sql"),
+            path.join("get_some_objects.sql.json"),
+        ]
+    );
+    assert!(std::fs::File::open(path.join("get_some_objects.sql.json")).is_err());
+    assert!(std::fs::File::open(path.join("update_some_objects.sql.json")).is_ok());
+}
+
+#[test]
+fn rust_module() {
+    let dir = create_input();
+    let path = dir.path().to_owned();
+    std::fs::create_dir_all(path.join("team")).unwrap();
+    std::fs::write(
+        path.join("team/count-users.sql.tera"),
+        "SELECT count(*) FROM \"users\"# WHERE team = $[team_id]",
+    )
+    .unwrap();
+    let module_path = path.join("out").join("queries.rs");
+
+    let report = build(
+        Options::builder()
+            .input(&path)
+            .placeholders(PlaceholderStyle::Postgres)
+            .rust_module(&module_path)
+            .build(),
+    )
+    .unwrap();
+
+    assert_eq!(
+        report.rust_module.as_ref().map(|o| o.status),
+        Some(OutputStatus::Written)
+    );
+    let get_some_objects = report
+        .rendered
+        .iter()
+        .find(|t| t.name == "get_some_objects")
+        .unwrap();
+    assert_eq!(
+        get_some_objects.parameters,
+        vec!["obj_id", "team_id", "user_id"]
+    );
+
+    let module = std::fs::read_to_string(&module_path).unwrap();
+    assert!(module.starts_with("// Autogenerated by sqlweld\n"));
+    let expected_sql = std::fs::read_to_string(path.join("get_some_objects.sql")).unwrap();
+    assert!(module.contains(&format!(
+        "pub const GET_SOME_OBJECTS: &str = r#\"{expected_sql}\"#;"
+    )));
+    assert!(module.contains(
+        "pub const GET_SOME_OBJECTS_PARAMETERS: &[&str] = &[\"obj_id\", \"team_id\", \"user_id\"];"
+    ));
+    // The raw string needs more hashes when the SQL contains `"#`.
+    assert!(module.contains("pub const TEAM_COUNT_USERS: &str = r##\""));
+    assert!(module.contains(
+        "pub const QUERIES: &[(&str, &str)] = &[\n    (\"get_some_objects\", GET_SOME_OBJECTS),\n    (\"team/count-users\", TEAM_COUNT_USERS),\n    (\"update_some_objects\", UPDATE_SOME_OBJECTS),\n];"
+    ));
+
+    // The module is checked along with the SQL files.
+    std::fs::write(&module_path, "").unwrap();
+    let err = build(
+        Options::builder()
+            .input(&path)
+            .placeholders(PlaceholderStyle::Postgres)
+            .rust_module(&module_path)
+            .check(true)
+            .build(),
+    )
+    .expect_err("should fail");
+    assert_eq!(
+        err.downcast_ref::<StaleFiles>(),
+        Some(&StaleFiles(vec![module_path]))
+    );
+}
+
+#[test]
+fn rust_module_name_conflict() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().to_owned();
+    let build_module = |names: &[&str]| {
+        for entry in std::fs::read_dir(&path).unwrap() {
+            std::fs::remove_file(entry.unwrap().path()).unwrap();
+        }
+        for name in names {
+            std::fs::write(path.join(format!("{name}.sql.tera")), "SELECT 1").unwrap();
+        }
+
+        build(
+            Options::builder()
+                .input(&path)
+                .rust_module(path.join("queries.rs"))
+                .build(),
+        )
+    };
+
+    for names in [
+        &["get-users", "get_users"][..],
+        &["foo", "foo_parameters"],
+        &["queries"],
+        &["query"],
+    ] {
+        let err = build_module(names).expect_err(&format!("{names:?} should fail"));
+        assert!(matches!(err.current_context(), Error::RustModule));
+    }
+
+    // Names that only start with a reserved name are fine.
+    build_module(&["queries_list"]).unwrap();
+}
+
+#[test]
+fn check_rust_module_without_templates() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().to_owned();
+    let module_path = path.join("queries.rs");
+    std::fs::write(path.join("get_users.sql.tera"), "SELECT 1").unwrap();
+
+    build(
+        Options::builder()
+            .input(&path)
+            .rust_module(&module_path)
+            .build(),
+    )
+    .unwrap();
+    std::fs::remove_file(path.join("get_users.sql.tera")).unwrap();
+
+    let err = build(
+        Options::builder()
+            .input(&path)
+            .rust_module(&module_path)
+            .check(true)
+            .build(),
+    )
+    .expect_err("should fail");
+    assert!(matches!(err.current_context(), Error::OutOfDate));
+    assert_eq!(
+        err.downcast_ref::<StaleFiles>(),
+        Some(&StaleFiles(vec![module_path]))
+    );
+}
+
+#[test]
+fn depfile() {
+    let dir = create_input();
+    let path = dir.path().to_owned();
+    std::fs::create_dir_all(path.join("sub dir")).unwrap();
+    std::fs::write(path.join("root.partial.sql.tera"), ROOT_PARTIAL).unwrap();
+    std::fs::write(path.join("sub dir/uses_root.sql.tera"), USES_ROOT_PARTIAL).unwrap();
+    std::fs::write(path.join("context.json"), "{}").unwrap();
+    let depfile_path = path.join("sqlweld.d");
+
+    let report = build(
+        Options::builder()
+            .input(&path)
+            .context_file(path.join("context.json"))
+            .manifest(true)
+            .depfile(&depfile_path)
+            .build(),
+    )
+    .unwrap();
+    assert_eq!(
+        report.depfile.as_ref().map(|o| o.status),
+        Some(OutputStatus::Written)
+    );
+
+    let p = |name: &str| path.join(name).display().to_string().replace(' ', "\\ ");
+    let expected = [
+        format!(
+            "{}: {} {} {}",
+            p("get_some_objects.sql"),
+            p("context.json"),
+            p("get_some_objects.sql.tera"),
+            p("perm_check.partial.sql.tera")
+        ),
+        format!(
+            "{}: {} {} {}",
+            p("get_some_objects.sql.json"),
+            p("context.json"),
+            p("get_some_objects.sql.tera"),
+            p("perm_check.partial.sql.tera")
+        ),
+        format!(
+            "{}: {} {} {}",
+            p("sub dir/uses_root.sql"),
+            p("context.json"),
+            p("root.partial.sql.tera"),
+            p("sub dir/uses_root.sql.tera")
+        ),
+        format!(
+            "{}: {} {} {}",
+            p("sub dir/uses_root.sql.json"),
+            p("context.json"),
+            p("root.partial.sql.tera"),
+            p("sub dir/uses_root.sql.tera")
+        ),
+        format!(
+            "{}: {} {} {}",
+            p("update_some_objects.sql"),
+            p("context.json"),
+            p("perm_check.partial.sql.tera"),
+            p("update_some_objects.sql.tera")
+        ),
+        format!(
+            "{}: {} {} {}",
+            p("update_some_objects.sql.json"),
+            p("context.json"),
+            p("perm_check.partial.sql.tera"),
+            p("update_some_objects.sql.tera")
+        ),
+    ]
+    .iter()
+    .map(|line| format!("{line}\n"))
+    .collect::<String>();
+    assert_eq!(std::fs::read_to_string(&depfile_path).unwrap(), expected);
+}
+
+#[test]
+fn render_query_without_writing() {
+    let dir = create_input();
+    let path = dir.path().to_owned();
+    std::fs::create_dir_all(path.join("sub")).unwrap();
+    std::fs::write(path.join("root.partial.sql.tera"), ROOT_PARTIAL).unwrap();
+    std::fs::write(path.join("sub/uses_root.sql.tera"), USES_ROOT_PARTIAL).unwrap();
+    let context_file = path.join("context.json");
+    std::fs::write(&context_file, "{}").unwrap();
+
+    let options = Options::builder()
+        .input(&path)
+        .context_file(&context_file)
+        .build();
+
+    let query =
+        crate::render_query(&options, std::path::Path::new("get_some_objects.sql.tera")).unwrap();
+    assert_eq!(query.name, "get_some_objects");
+    assert_eq!(query.sql, apply_header(HEADER, EXPECTED_GET_SOME_OBJECTS));
+    assert_eq!(query.parameters, vec!["obj_id", "team_id", "user_id"]);
+    assert_eq!(
+        query.files,
+        vec![
+            path.join("get_some_objects.sql.tera"),
+            path.join("perm_check.partial.sql.tera"),
+            context_file.clone(),
+        ]
+    );
+
+    let query = crate::render_query(&options, &path.join("sub/uses_root.sql.tera")).unwrap();
+    assert_eq!(query.sql, apply_header(HEADER, EXPECTED_USES_ROOT_PARTIAL));
+    assert_eq!(
+        query.files,
+        vec![
+            path.join("sub/uses_root.sql.tera"),
+            path.join("root.partial.sql.tera"),
+            context_file,
+        ]
+    );
+
+    assert!(!path.join("get_some_objects.sql").exists());
+    let err = crate::render_query(
+        &options,
+        std::path::Path::new("perm_check.partial.sql.tera"),
+    )
+    .expect_err("partials can't be rendered on their own");
+    assert!(matches!(err.current_context(), Error::ReadTemplate));
+}
+
+#[test]
+fn rerun_if_changed_paths() {
+    let dir = create_input();
+    let path = dir.path().to_owned();
+    let shared = tempfile::tempdir().unwrap();
+    std::fs::create_dir_all(path.join("sub/empty")).unwrap();
+    std::fs::write(path.join("sub/q.sql.tera"), "SELECT 1").unwrap();
+    std::fs::write(shared.path().join("shared.partial.sql.tera"), "x").unwrap();
+    std::fs::write(shared.path().join("not_rendered.sql.tera"), "x").unwrap();
+    std::fs::write(path.join("context.json"), "{}").unwrap();
+    std::fs::write(
+        path.join("sqlweld.toml"),
+        "context-files = [\"context.json\"]\n",
+    )
+    .unwrap();
+
+    let options = Options::builder()
+        .input(&path)
+        .partials_dir(shared.path())
+        .build()
+        .with_config_file_from(&path)
+        .unwrap();
+
+    let (templates, dirs) = crate::find_all_templates(&path, &options);
+    let paths = crate::rerun_if_changed_paths(&options, &templates, &dirs);
+
+    let mut expected = vec![
+        path.clone(),
+        path.join("context.json"),
+        path.join("get_some_objects.sql.tera"),
+        path.join("perm_check.partial.sql.tera"),
+        path.join("sqlweld.toml"),
+        path.join("sub"),
+        path.join("sub/empty"),
+        path.join("sub/q.sql.tera"),
+        path.join("update_some_objects.sql.tera"),
+        shared.path().to_path_buf(),
+        shared.path().join("shared.partial.sql.tera"),
+    ];
+    expected.sort();
+    assert_eq!(paths, expected);
+}
+
+#[test]
+fn context_files_and_vars() {
+    let dir = create_input();
+    let path = dir.path().to_owned();
+
+    std::fs::write(
+        path.join("context_test.sql.tera"),
+        "SELECT * FROM {{ table }} WHERE {{ filter.column }} = {{ filter.value }} LIMIT {{ limit }}",
+    )
+    .unwrap();
+
+    let context_dir = tempfile::tempdir().unwrap();
+    let json_path = context_dir.path().join("base.json");
+    std::fs::write(
+        &json_path,
+        r#"{ "table": "base_table", "filter": { "column": "id", "value": 1 }, "limit": 5 }"#,
+    )
+    .unwrap();
+    let toml_path = context_dir.path().join("override.toml");
+    std::fs::write(&toml_path, "table = \"toml_table\"\n[filter]\nvalue = 2\n").unwrap();
+    let yaml_path = context_dir.path().join("override.yaml");
+    std::fs::write(&yaml_path, "filter:\n  column: team\n").unwrap();
+
+    build(Options {
+        input: Some(path.clone()),
+        header: Some("".to_string()),
+        context_files: vec![json_path, toml_path, yaml_path],
+        vars: vec![
+            ("limit".to_string(), "10".to_string()),
+            ("filter.value".to_string(), "3".to_string()),
+        ],
+        ..Default::default()
+    })
+    .unwrap();
+
+    assert_eq!(
+        std::fs::read_to_string(path.join("context_test.sql")).unwrap(),
+        "SELECT * FROM toml_table WHERE team = 3 LIMIT 10"
+    );
+}
+
+#[test]
+fn context_type_conflict() {
+    let dir = create_input();
+    let path = dir.path().to_owned();
+
+    let context_dir = tempfile::tempdir().unwrap();
+    let first = context_dir.path().join("first.json");
+    std::fs::write(&first, r#"{ "filter": { "column": "id" } }"#).unwrap();
+    let second = context_dir.path().join("second.yml");
+    std::fs::write(&second, "filter: id\n").unwrap();
+
+    let err = build(Options {
+        input: Some(path.clone()),
+        context_files: vec![first, second],
+        ..Default::default()
+    })
+    .expect_err("should fail");
+
+    assert!(matches!(err.current_context(), Error::ContextConflict));
+}
+
+#[test]
+fn vars_override_any_type() {
+    let dir = create_input();
+    let path = dir.path().to_owned();
+
+    std::fs::write(
+        path.join("context_test.sql.tera"),
+        "SELECT * FROM t WHERE {{ filter }} LIMIT {{ limit }}",
+    )
+    .unwrap();
+
+    let context_dir = tempfile::tempdir().unwrap();
+    let context_path = context_dir.path().join("ctx.toml");
+    std::fs::write(&context_path, "limit = \"10\"\n[filter]\ncolumn = \"id\"\n").unwrap();
+
+    build(Options {
+        input: Some(path.clone()),
+        header: Some("".to_string()),
+        context_files: vec![context_path],
+        vars: vec![
+            ("limit".to_string(), "20".to_string()),
+            ("filter".to_string(), "true".to_string()),
+        ],
+        ..Default::default()
+    })
+    .unwrap();
+
+    assert_eq!(
+        std::fs::read_to_string(path.join("context_test.sql")).unwrap(),
+        "SELECT * FROM t WHERE true LIMIT 20"
+    );
+}
+
+#[test]
+fn parse_var_arguments() {
+    use crate::context::parse_var;
+
+    assert_eq!(
+        parse_var("a.b=c=d").unwrap(),
+        ("a.b".to_string(), "c=d".to_string())
+    );
+    assert!(parse_var("novalue").is_err());
+    assert!(parse_var("a..b=1").is_err());
+}
+
+#[test]
+fn config_file() {
+    use crate::config::{apply_config_file, find_config_file};
+
+    let dir = create_input();
+    let path = dir.path().to_owned();
+    let project = tempfile::tempdir().unwrap();
+    std::fs::write(
+        project.path().join("sqlweld.toml"),
+        format!(
+            "input = {:?}\noutput = \"generated\"\nheader = \"from config\"\nmanifest = true\n[vars]\nlimit = 5\n",
+            path
+        ),
+    )
+    .unwrap();
+
+    let nested = project.path().join("src").join("nested");
+    std::fs::create_dir_all(&nested).unwrap();
+    let config_path = find_config_file(&nested).unwrap().unwrap();
+    assert_eq!(config_path, project.path().join("sqlweld.toml"));
+
+    let options = apply_config_file(
+        Options {
+            header: Some("from cli".to_string()),
+            vars: vec![("limit".to_string(), "10".to_string())],
+            ..Default::default()
+        },
+        &config_path,
+    )
+    .unwrap();
+
+    assert_eq!(options.input, Some(path.clone()));
+    assert_eq!(options.output, Some(project.path().join("generated")));
+    assert_eq!(options.header.as_deref(), Some("from cli"));
+    assert!(options.manifest);
+    assert_eq!(
+        options.config_vars,
+        vec![("limit".to_string(), serde_json::json!(5))]
+    );
+    assert_eq!(options.vars, vec![("limit".to_string(), "10".to_string())]);
+
+    build(options).unwrap();
+    assert_eq!(
+        std::fs::read_to_string(
+            project
+                .path()
+                .join("generated")
+                .join("get_some_objects.sql")
+        )
+        .unwrap(),
+        apply_header("-- from cli", EXPECTED_GET_SOME_OBJECTS)
+    );
+}
+
+#[test]
+fn config_vars_keep_types() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().to_owned();
+    std::fs::write(
+        path.join("types.sql.tera"),
+        "{% for value in [v, w, n, b] %}{% if value is string %}string {% else %}other {% endif %}{% endfor %}{{ filter.column }}",
+    )
+    .unwrap();
+
+    let config_path = path.join("sqlweld.toml");
+    std::fs::write(
+        &config_path,
+        "[vars]\nv = \"10\"\nw = \"true\"\nn = 10\nb = true\n\"filter.column\" = \"id\"\n",
+    )
+    .unwrap();
+
+    let options = crate::config::apply_config_file(
+        Options::builder().input(&path).header("").build(),
+        &config_path,
+    )
+    .unwrap();
+    build(options).unwrap();
+
+    assert_eq!(
+        std::fs::read_to_string(path.join("types.sql")).unwrap(),
+        "string string other other id"
+    );
+}
+
+#[test]
+fn config_overridden_by_command_line() {
+    use clap::Parser;
+
+    let project = tempfile::tempdir().unwrap();
+    let config_path = project.path().join("sqlweld.toml");
+    std::fs::write(
+        &config_path,
+        "prune = true\nmanifest = true\nalways-write = true\ncheck-ignored-dirs = true\n\
+        partials-dirs = [\"shared\"]\nformatter-env = [\"HOME\"]\n",
+    )
+    .unwrap();
+
+    let options = Options::parse_from([
+        "sqlweld",
+        "--no-prune",
+        "--no-always-write",
+        "--check-ignored-dirs",
+        "--no-check-ignored-dirs",
+        "--partials-dir",
+        "cli_shared",
+        "--formatter-env",
+        "NODE_OPTIONS",
+    ]);
+    let options = crate::config::apply_config_file(options, &config_path).unwrap();
+    assert!(!options.prune);
+    assert!(!options.always_write);
+    assert!(!options.check_ignored_dirs);
+    assert!(options.manifest);
+    assert_eq!(
+        options.partials_dirs,
+        vec![std::path::PathBuf::from("cli_shared")]
+    );
+    assert_eq!(options.formatter_env, vec!["NODE_OPTIONS".to_string()]);
+
+    let options = Options::builder().manifest(false).build();
+    let options = crate::config::apply_config_file(options, &config_path).unwrap();
+    assert!(!options.manifest);
+    assert!(options.prune);
+    assert_eq!(options.partials_dirs, vec![project.path().join("shared")]);
+}
+
+#[test]
+fn config_from_cargo_metadata() {
+    use crate::config::{apply_config_file, find_config_file};
+
+    let project = tempfile::tempdir().unwrap();
+    let nested = project.path().join("crate");
+    std::fs::create_dir_all(&nested).unwrap();
+
+    // A Cargo.toml without sqlweld metadata is skipped.
+    std::fs::write(nested.join("Cargo.toml"), "[package]\nname = \"inner\"\n").unwrap();
+    std::fs::write(
+        project.path().join("Cargo.toml"),
+        "[package]\nname = \"outer\"\n[package.metadata.sqlweld]\ninput = \"queries\"\nplaceholders = \"sqlite\"\n",
+    )
+    .unwrap();
+
+    let config_path = find_config_file(&nested).unwrap().unwrap();
+    assert_eq!(config_path, project.path().join("Cargo.toml"));
+
+    let options = apply_config_file(Options::default(), &config_path).unwrap();
+    assert_eq!(options.input, Some(project.path().join("queries")));
+    assert_eq!(options.placeholders, Some(PlaceholderStyle::Question));
+}
+
+#[test]
+fn config_file_unknown_key() {
+    let project = tempfile::tempdir().unwrap();
+    let config_path = project.path().join("sqlweld.toml");
+    std::fs::write(&config_path, "not_an_option = true\n").unwrap();
+
+    let err = crate::config::apply_config_file(Options::default(), &config_path)
+        .expect_err("should fail");
+    assert!(matches!(err.current_context(), Error::Config));
+}
+
+fn watch_state(path: &std::path::Path) -> crate::watch::WatchState {
+    let options = Options {
+        input: Some(path.to_owned()),
+        ..Default::default()
+    };
+
+    let paths = crate::find_template_files(path, &options);
+    let templates = crate::TemplateSet::load(path.to_owned(), &options, paths).unwrap();
+    crate::watch::WatchState::new(options, templates, tera::Context::new())
+}
+
+#[test]
+fn watch_rerenders_dependents_of_changed_partial() {
+    let dir = create_input();
+    let path = dir.path().to_owned();
+    std::fs::write(path.join("root.partial.sql.tera"), ROOT_PARTIAL).unwrap();
+    std::fs::write(path.join("uses_root.sql.tera"), USES_ROOT_PARTIAL).unwrap();
+
+    let mut state = watch_state(&path);
+
+    std::fs::write(
+        path.join("perm_check.partial.sql.tera"),
+        PERM_CHECK.replace("permissions", "new_permissions"),
+    )
+    .unwrap();
+
+    let summary = state.apply_changes([path.join("perm_check.partial.sql.tera")].into());
+    assert!(summary.errors.is_empty(), "{:?}", summary.errors);
+    assert_eq!(
+        summary.changed,
+        vec![path.join("perm_check.partial.sql.tera")]
+    );
+    assert_eq!(
+        summary.rendered,
+        vec![
+            path.join("get_some_objects.sql.tera"),
+            path.join("update_some_objects.sql.tera"),
+        ]
+    );
+
+    assert_eq!(
+        std::fs::read_to_string(path.join("get_some_objects.sql")).unwrap(),
+        apply_header(HEADER, EXPECTED_GET_SOME_OBJECTS).replace("permissions", "new_permissions")
+    );
+    assert!(std::fs::File::open(path.join("uses_root.sql")).is_err());
+}
+
+#[test]
+fn watch_adds_and_removes_templates() {
+    let dir = create_input();
+    let path = dir.path().to_owned();
+
+    let mut state = watch_state(&path);
+
+    std::fs::write(path.join("root.partial.sql.tera"), ROOT_PARTIAL).unwrap();
+    std::fs::write(path.join("uses_root.sql.tera"), USES_ROOT_PARTIAL).unwrap();
+    std::fs::remove_file(path.join("get_some_objects.sql.tera")).unwrap();
+
+    let summary = state.apply_changes(
+        [
+            path.join("root.partial.sql.tera"),
+            path.join("uses_root.sql.tera"),
+            path.join("get_some_objects.sql.tera"),
+        ]
+        .into(),
+    );
+    assert!(summary.errors.is_empty(), "{:?}", summary.errors);
+    assert_eq!(summary.changed.len(), 3);
+    assert_eq!(summary.rendered, vec![path.join("uses_root.sql.tera")]);
+
+    assert_eq!(
+        std::fs::read_to_string(path.join("uses_root.sql")).unwrap(),
+        apply_header(HEADER, EXPECTED_USES_ROOT_PARTIAL)
+    );
+    assert!(state
+        .templates
+        .tera
+        .get_template("get_some_objects.sql.tera")
+        .is_err());
+}
+
+#[test]
+fn watch_follows_nearest_partial() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().to_owned();
+
+    std::fs::create_dir_all(path.join("team")).unwrap();
+    std::fs::write(path.join("table.partial.sql.tera"), "root_table").unwrap();
+    std::fs::write(
+        path.join("team/q.sql.tera"),
+        "SELECT * FROM {% include \"table\" %}",
+    )
+    .unwrap();
+
+    let mut state = watch_state(&path);
+    state.options.header = Some(String::new());
+
+    // A closer partial with the same name takes over.
+    std::fs::write(path.join("team/table.partial.sql.tera"), "team_table").unwrap();
+    let summary = state.apply_changes([path.join("team/table.partial.sql.tera")].into());
+    assert!(summary.errors.is_empty(), "{:?}", summary.errors);
+    assert_eq!(summary.rendered, vec![path.join("team/q.sql.tera")]);
+    assert_eq!(
+        std::fs::read_to_string(path.join("team/q.sql")).unwrap(),
+        "SELECT * FROM team_table"
+    );
+
+    // And removing it goes back to the one in the parent directory.
+    std::fs::remove_file(path.join("team/table.partial.sql.tera")).unwrap();
+    let summary = state.apply_changes([path.join("team/table.partial.sql.tera")].into());
+    assert!(summary.errors.is_empty(), "{:?}", summary.errors);
+    assert_eq!(summary.rendered, vec![path.join("team/q.sql.tera")]);
+    assert_eq!(
+        std::fs::read_to_string(path.join("team/q.sql")).unwrap(),
+        "SELECT * FROM root_table"
+    );
+}
+
+#[test]
+fn watch_restores_shadowed_shared_partial() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().to_owned();
+    let shared = tempfile::tempdir().unwrap();
+
+    std::fs::write(shared.path().join("table.partial.sql.tera"), "shared_table").unwrap();
+    std::fs::write(path.join("table.partial.sql.tera"), "local_table").unwrap();
+    std::fs::write(
+        path.join("q.sql.tera"),
+        "SELECT * FROM {% include \"table\" %}",
+    )
+    .unwrap();
+
+    let options = Options::builder()
+        .input(&path)
+        .partials_dir(shared.path())
+        .header("")
+        .build();
+    let (paths, _) = crate::find_all_templates(&path, &options);
+    let templates = crate::TemplateSet::load(path.clone(), &options, paths).unwrap();
+    let mut state = crate::watch::WatchState::new(options, templates, tera::Context::new());
+
+    std::fs::remove_file(path.join("table.partial.sql.tera")).unwrap();
+    let summary = state.apply_changes([path.join("table.partial.sql.tera")].into());
+    assert!(summary.errors.is_empty(), "{:?}", summary.errors);
+    assert_eq!(summary.rendered, vec![path.join("q.sql.tera")]);
+    assert_eq!(
+        std::fs::read_to_string(path.join("q.sql")).unwrap(),
+        "SELECT * FROM shared_table"
+    );
+}
+
+#[test]
+fn watch_shared_partial_change() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().to_owned();
+    let shared = tempfile::tempdir().unwrap();
+    std::fs::create_dir(shared.path().join("sub")).unwrap();
+    // The file watcher reports canonical paths, which won't match this one.
+    let shared_dir = shared.path().join("sub").join("..");
+
+    std::fs::write(shared.path().join("table.partial.sql.tera"), "old_table").unwrap();
+    std::fs::write(
+        path.join("q.sql.tera"),
+        "SELECT * FROM {% include \"table\" %}",
+    )
+    .unwrap();
+
+    let options = Options::builder()
+        .input(&path)
+        .partials_dir(&shared_dir)
+        .header("")
+        .build();
+    let (paths, _) = crate::find_all_templates(&path, &options);
+    let templates = crate::TemplateSet::load(path.clone(), &options, paths).unwrap();
+    let mut state = crate::watch::WatchState::new(options, templates, tera::Context::new());
+
+    let changed = shared
+        .path()
+        .canonicalize()
+        .unwrap()
+        .join("table.partial.sql.tera");
+    std::fs::write(&changed, "new_table").unwrap();
+    // Normal templates in a partials directory are ignored.
+    let ignored = shared.path().join("other.sql.tera");
+    std::fs::write(&ignored, "SELECT 1").unwrap();
+
+    let summary = state.apply_changes([changed, ignored].into());
+    assert!(summary.errors.is_empty(), "{:?}", summary.errors);
+    assert_eq!(
+        summary.changed,
+        vec![shared_dir.join("table.partial.sql.tera")]
+    );
+    assert_eq!(summary.rendered, vec![path.join("q.sql.tera")]);
+    assert_eq!(
+        std::fs::read_to_string(path.join("q.sql")).unwrap(),
+        "SELECT * FROM new_table"
+    );
+}
+
+#[test]
+fn watch_starts_with_failing_template() {
+    let dir = create_input();
+    let path = dir.path().to_owned();
+    std::fs::write(path.join("broken.sql.tera"), "SELECT {{ missing_var }}").unwrap();
+    std::fs::write(path.join("unparsable.sql.tera"), "SELECT {{ 1 +").unwrap();
+
+    let options = Options::builder().input(&path).watch(true).build();
+    let paths = crate::find_template_files(&path, &options);
+    let (mut state, summary) =
+        crate::watch::WatchState::load(options, path.clone(), paths).unwrap();
+    assert_eq!(summary.errors.len(), 2, "{:?}", summary.errors);
+    assert!(matches!(
+        summary.errors[0].current_context(),
+        Error::ReadTemplate
+    ));
+    assert!(matches!(summary.errors[1].current_context(), Error::Render));
+    // The other templates are still rendered.
+    assert_eq!(
+        std::fs::read_to_string(path.join("update_some_objects.sql")).unwrap(),
+        apply_header(HEADER, EXPECTED_UPDATE_SOME_OBJECTS)
+    );
+
+    std::fs::write(path.join("broken.sql.tera"), "SELECT 1").unwrap();
+    std::fs::write(path.join("unparsable.sql.tera"), "SELECT 2").unwrap();
+    let summary = state.apply_changes(
+        [
+            path.join("broken.sql.tera"),
+            path.join("unparsable.sql.tera"),
+        ]
+        .into(),
+    );
+    assert!(summary.errors.is_empty(), "{:?}", summary.errors);
+    assert_eq!(
+        strip_header(&std::fs::read_to_string(path.join("broken.sql")).unwrap()),
+        "SELECT 1"
+    );
+    assert_eq!(
+        strip_header(&std::fs::read_to_string(path.join("unparsable.sql")).unwrap()),
+        "SELECT 2"
+    );
+}
+
+#[test]
+fn watch_reports_errors_and_recovers() {
+    let dir = create_input();
+    let path = dir.path().to_owned();
+
+    let mut state = watch_state(&path);
+
+    std::fs::write(
+        path.join("update_some_objects.sql.tera"),
+        "{{ missing_var }}",
+    )
+    .unwrap();
+    let summary = state.apply_changes([path.join("update_some_objects.sql.tera")].into());
+    assert_eq!(summary.errors.len(), 1);
+    assert!(matches!(summary.errors[0].current_context(), Error::Render));
+
+    std::fs::write(
+        path.join("update_some_objects.sql.tera"),
+        UPDATE_SOME_OBJECTS,
+    )
+    .unwrap();
+    let summary = state.apply_changes([path.join("update_some_objects.sql.tera")].into());
+    assert!(summary.errors.is_empty(), "{:?}", summary.errors);
+    assert_eq!(
+        std::fs::read_to_string(path.join("update_some_objects.sql")).unwrap(),
+        apply_header(HEADER, EXPECTED_UPDATE_SOME_OBJECTS)
+    );
+}
+
+#[test]
+fn builtin_format() {
+    let dir = create_input();
+    let path = dir.path().to_owned();
+    build(
+        Options::builder()
+            .input(&path)
+            .format(Format::Builtin)
+            .validate(SqlDialect::Postgres)
+            .build(),
+    )
+    .unwrap();
+
+    let output = std::fs::read_to_string(path.join("get_some_objects.sql")).unwrap();
+    assert_eq!(
+        output,
+        r#"-- Autogenerated by sqlweld
+
+SELECT *
+FROM some_objects
+WHERE
+  id = $[obj_id]
+  AND team = $[team_id]
+  AND EXISTS (
+    SELECT 1
+    FROM permissions
+    WHERE
+      user_id = $[user_id]
+      AND team_id = $[team_id]
+      AND action = 'read'
+      AND object_type = 'some_objects'
+  )
+"#
+    );
+}
+
+#[test]
+fn builtin_format_settings() {
+    use crate::format::{format_sql, FormatOptions};
+
+    let sql = "select a, b, count(*) from t left join u on u.id = t.id \
+        where t.x between 1 and 2 and u.y in (1, 2, 3) group by a, b";
+    assert_eq!(
+        format_sql(sql, &FormatOptions::default()),
+        "SELECT a, b, count(*)\nFROM t\nLEFT JOIN u ON u.id = t.id\n\
+        WHERE t.x BETWEEN 1 AND 2 AND u.y IN (1, 2, 3)\nGROUP BY a, b\n"
+    );
+
+    let options = FormatOptions {
+        indent: 4,
+        keyword_case: KeywordCase::Lower,
+        line_width: 30,
+    };
+    assert_eq!(
+        format_sql(sql, &options),
+        r#"select a, b, count(*)
+from t
+left join u on u.id = t.id
+where
+    t.x between 1 and 2
+    and u.y in (1, 2, 3)
+group by a, b
+"#
+    );
+
+    let options = FormatOptions {
+        keyword_case: KeywordCase::Preserve,
+        line_width: 20,
+        ..Default::default()
+    };
+    assert_eq!(
+        format_sql(
+            "Select coalesce(first_value, second_value) From t",
+            &options
+        ),
+        "Select\n  coalesce(\n    first_value,\n    second_value\n  )\nFrom t\n"
+    );
+}
+
+#[test]
+fn builtin_format_keeps_tokens() {
+    use crate::format::{format_sql, FormatOptions};
+
+    let options = FormatOptions::default();
+    assert_eq!(
+        format_sql(
+            "select 'a  b', E'it\\'s', $$x  y$$, \"Mixed  Case\" from t -- the table\n;select 1",
+            &options
+        ),
+        "SELECT 'a  b', E'it\\'s', $$x  y$$, \"Mixed  Case\"\nFROM t -- the table\n;\n\nSELECT 1\n"
+    );
+    assert_eq!(
+        format_sql("select a=-1, %(name)s, :name, ?, x::int from t", &options),
+        "SELECT a = -1, %(name)s, :name, ?, x::int\nFROM t\n"
+    );
+
+    assert_eq!(
+        format_sql(
+            "with x as (select ts::timestamp with time zone from t) select * from x",
+            &options
+        ),
+        "WITH\n  x AS (\n    SELECT ts::timestamp with time zone\n    FROM t\n  )\nSELECT *\nFROM x\n"
+    );
+    assert_eq!(
+        format_sql("select $€ from t", &options),
+        "SELECT $€\nFROM t\n"
+    );
+
+    // Unbalanced parentheses are left alone.
+    assert_eq!(format_sql("select (a from t", &options), "select (a from t");
+}
+
+#[test]
+fn formatter_arguments() {
+    let dir = create_input();
+    let path = dir.path().to_owned();
+    build(
+        Options::builder()
+            .input(&path)
+            .formatter_args(["sh", "-c", "echo \"-- $0 $1\"; cat", "{path}", "{dialect}"])
+            .dialect(SqlDialect::Postgres)
+            .build(),
+    )
+    .unwrap();
+
+    let output = std::fs::read_to_string(path.join("get_some_objects.sql")).unwrap();
+    let first_line = output.lines().next().unwrap();
+    assert_eq!(
+        first_line,
+        format!(
+            "-- {} postgres",
+            path.join("get_some_objects.sql").display()
+        )
+    );
+
+    build(
+        Options::builder()
+            .input(&path)
+            .formatter("sed -e 's/SELECT \\* FROM/select * from/'")
+            .build(),
+    )
+    .unwrap();
+    let output = std::fs::read_to_string(path.join("get_some_objects.sql")).unwrap();
+    assert!(output.contains("select * from some_objects"));
+
+    let err = build(
+        Options::builder()
+            .input(&path)
+            .formatter("sed -e 's/unclosed")
+            .build(),
+    )
+    .expect_err("unclosed quote");
+    assert!(matches!(err.current_context(), Error::Formatter));
+
+    let err = build(
+        Options::builder()
+            .input(&path)
+            .formatter("sed -e s/{dialect}//")
+            .build(),
+    )
+    .expect_err("no dialect");
+    assert!(format!("{err:?}").contains("no dialect is set"));
+}
+
+#[test]
+fn formatter_timeout() {
+    let dir = create_input();
+    let path = dir.path().to_owned();
+    let start = std::time::Instant::now();
+    let err = build(
+        Options::builder()
+            .input(&path)
+            .formatter("sleep 10")
+            .formatter_timeout(std::time::Duration::from_millis(200))
+            .build(),
+    )
+    .expect_err("formatter should time out");
+
+    assert!(matches!(err.current_context(), Error::Formatter));
+    assert!(format!("{err:?}").contains("did not finish within 200ms"));
+    assert!(start.elapsed() < std::time::Duration::from_secs(5));
+}
+
+#[test]
+fn formatter_env() {
+    let dir = create_input();
+    let path = dir.path().to_owned();
+    // Cargo sets these variables when running tests.
+    build(
+        Options::builder()
+            .input(&path)
+            .formatter_args([
+                "sh",
+                "-c",
+                "echo \"-- ${CARGO_PKG_NAME:-unset} ${CARGO_MANIFEST_DIR:-unset}\"; cat",
+            ])
+            .formatter_env("CARGO_PKG_NAME")
+            .build(),
+    )
+    .unwrap();
+
+    let output = std::fs::read_to_string(path.join("get_some_objects.sql")).unwrap();
+    assert!(output.starts_with("-- sqlweld unset\n"));
+}
+
+#[test]
+fn config_formatter_settings() {
+    use crate::{config::apply_config_file, formatter::FormatterCommand};
+
+    let project = tempfile::tempdir().unwrap();
+    let config_path = project.path().join("sqlweld.toml");
+    std::fs::write(
+        &config_path,
+        "formatter = [\"sql-formatter\", \"--language\", \"{dialect}\"]\n\
+        formatter-timeout = \"1m 30s\"\nformatter-env = [\"HOME\"]\ndialect = \"mysql\"\n",
+    )
+    .unwrap();
+
+    let options = apply_config_file(Options::default(), &config_path).unwrap();
+    assert_eq!(
+        options.formatter,
+        Some(FormatterCommand::Args(vec![
+            "sql-formatter".to_string(),
+            "--language".to_string(),
+            "{dialect}".to_string()
+        ]))
+    );
+    assert_eq!(
+        options.formatter_timeout,
+        Some(std::time::Duration::from_secs(90))
+    );
+    assert_eq!(options.formatter_env, vec!["HOME".to_string()]);
+    assert_eq!(options.dialect, Some(SqlDialect::Mysql));
+
+    std::fs::write(&config_path, "formatter = \"pg_format -s 2 -\"\n").unwrap();
+    let options = apply_config_file(Options::default(), &config_path).unwrap();
+    assert_eq!(
+        options.formatter,
+        Some(FormatterCommand::Shell("pg_format -s 2 -".to_string()))
+    );
+}
+
+#[test]
+fn batch_formatter() {
+    let dir = create_input();
+    let path = dir.path().to_owned();
+    let runs = tempfile::tempdir().unwrap();
+    let runs_path = runs.path().join("runs");
+
+    let script = format!(
+        "echo run >> {}; for f in \"$@\"; do sed -i -e 's/SELECT/select/' \"$f\"; done",
+        runs_path.display()
+    );
+    let report = build(
+        Options::builder()
+            .input(&path)
+            .formatter_args(["sh", "-c", &script, "sh", "{paths}"])
+            .formatter_mode(FormatterMode::Batch)
+            .build(),
+    )
+    .unwrap();
+
+    assert_eq!(std::fs::read_to_string(&runs_path).unwrap(), "run\n");
+    assert_eq!(report.rendered.len(), 2);
+    for output in report.outputs() {
+        let contents = std::fs::read_to_string(&output.path).unwrap();
+        assert!(contents.contains("select"), "{contents}");
+        assert!(!contents.contains("SELECT"), "{contents}");
+    }
+
+    let err = build(
+        Options::builder()
+            .input(&path)
+            .formatter("sed -i -e s/a/b/ {path}")
+            .formatter_mode(FormatterMode::Batch)
+            .build(),
+    )
+    .expect_err("{path} in batch mode");
+    assert!(format!("{err:?}").contains("{path} can not be used in batch mode"));
+}
+
+const LIST_OBJECTS: &str = r#"SELECT * FROM some_objects
+{% if archived %}WHERE archived{% else %}WHERE NOT archived{% endif %}
+{% if limit %}LIMIT {{ limit }}{% endif %}
+"#;
+
+const LIST_OBJECTS_TESTS: &str = r#"
+[context]
+limit = 10
+
+[[case]]
+name = "active"
+context = { archived = false }
+expected = """
+SELECT * FROM some_objects
+WHERE NOT archived
+LIMIT 10
+"""
+
+[[case]]
+context = { archived = true, limit = 0 }
+contains = ["WHERE archived"]
+not-contains = ["LIMIT"]
+matches = ['(?m)^SELECT \* FROM some_objects$']
+"#;
+
+#[test]
+fn template_tests() {
+    let dir = create_input();
+    let path = dir.path().to_owned();
+    std::fs::write(path.join("list_objects.sql.tera"), LIST_OBJECTS).unwrap();
+    std::fs::write(
+        path.join("list_objects.sql.tera.test.toml"),
+        LIST_OBJECTS_TESTS,
+    )
+    .unwrap();
+
+    let report = run_tests(Options::builder().input(&path).var("limit", "5").build()).unwrap();
+    assert_eq!(report.passed(), 2);
+    assert_eq!(report.failed(), 0);
+    assert_eq!(report.cases[0].name, "active");
+    assert_eq!(report.cases[1].name, "case 2");
+    assert_eq!(
+        report.cases[0].test_file,
+        path.join("list_objects.sql.tera.test.toml")
+    );
+    assert!(
+        !path.join("list_objects.sql").exists(),
+        "tests should not write output"
+    );
+}
+
+#[test]
+fn template_test_failures() {
+    let dir = create_input();
+    let path = dir.path().to_owned();
+    std::fs::write(path.join("list_objects.sql.tera"), LIST_OBJECTS).unwrap();
+    std::fs::write(
+        path.join("list_objects.sql.tera.test.toml"),
+        LIST_OBJECTS_TESTS
+            .replace("LIMIT 10\n\"\"\"", "LIMIT 20\n\"\"\"")
+            .replace("WHERE archived\"", "WHERE deleted\""),
+    )
+    .unwrap();
+
+    let err = run_tests(Options::builder().input(&path).build()).expect_err("tests should fail");
+    assert!(matches!(err.current_context(), Error::TestFailures));
+
+    let report = err.downcast_ref::<TestReport>().unwrap();
+    assert_eq!(report.failed(), 2);
+
+    let diagnostics = report_diagnostics(&err);
+    assert_eq!(diagnostics.len(), 2);
+    assert!(diagnostics.iter().all(|d| d.code == "test-failed"));
+    let active = diagnostics
+        .iter()
+        .find(|d| d.message == "Test `active` failed")
+        .unwrap();
+    assert_eq!(
+        active.location.as_ref().unwrap().path,
+        path.join("list_objects.sql.tera.test.toml")
+    );
+    assert!(
+        active.notes[1].contains("-LIMIT 20\n+LIMIT 10"),
+        "{}",
+        active.notes[1]
+    );
+
+    let second = diagnostics
+        .iter()
+        .find(|d| d.message == "Test `case 2` failed")
+        .unwrap();
+    assert_eq!(
+        second.notes[1],
+        "The output does not contain \"WHERE deleted\""
+    );
+
+    std::fs::write(
+        path.join("list_objects.sql.tera.test.toml"),
+        "[[case]]\nmatches = ['(unclosed']\n",
+    )
+    .unwrap();
+    let err = run_tests(Options::builder().input(&path).build()).expect_err("invalid regex");
+    assert!(matches!(err.current_context(), Error::ReadTest));
 }
@@ -0,0 +1,99 @@
+use sqlparser::{
+    dialect::{Dialect, GenericDialect, MySqlDialect, PostgreSqlDialect, SQLiteDialect},
+    parser::{Parser, ParserError},
+};
+
+use crate::placeholders::{mask_placeholders, PlaceholderStyle};
+
+/// The SQL dialect used to check that generated queries parse.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, serde::Serialize, serde::Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum SqlDialect {
+    #[value(alias = "postgresql")]
+    #[serde(alias = "postgresql")]
+    Postgres,
+    Mysql,
+    Sqlite,
+    /// A permissive dialect that accepts most standard SQL.
+    Generic,
+}
+
+impl SqlDialect {
+    fn parser_dialect(&self) -> Box<dyn Dialect> {
+        match self {
+            SqlDialect::Postgres => Box::new(PostgreSqlDialect {}),
+            SqlDialect::Mysql => Box::new(MySqlDialect {}),
+            SqlDialect::Sqlite => Box::new(SQLiteDialect {}),
+            SqlDialect::Generic => Box::new(GenericDialect {}),
+        }
+    }
+
+    /// The name of the dialect, as it is given on the command line.
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            SqlDialect::Postgres => "postgres",
+            SqlDialect::Mysql => "mysql",
+            SqlDialect::Sqlite => "sqlite",
+            SqlDialect::Generic => "generic",
+        }
+    }
+
+    /// The placeholder style that the dialect's parser understands.
+    fn placeholder_style(&self) -> PlaceholderStyle {
+        match self {
+            SqlDialect::Postgres => PlaceholderStyle::Postgres,
+            _ => PlaceholderStyle::Question,
+        }
+    }
+}
+
+/// A problem found while parsing generated SQL.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct SqlError {
+    pub message: String,
+    /// The line and column of the problem, starting at 1.
+    pub position: Option<(usize, usize)>,
+    /// The text at the position which the parser did not expect, if known.
+    pub found: Option<String>,
+}
+
+/// Check that `sql` parses in the given dialect. `$[name]` placeholders which have not been
+/// compiled are treated as parameters. `compiled` is the style that the placeholders were compiled
+/// into, if any.
+pub(crate) fn validate_sql(
+    sql: &str,
+    dialect: SqlDialect,
+    compiled: Option<PlaceholderStyle>,
+) -> Result<(), SqlError> {
+    let pyformat = compiled == Some(PlaceholderStyle::Pyformat);
+    let masked = mask_placeholders(sql, dialect.placeholder_style(), pyformat);
+    let error = match Parser::parse_sql(dialect.parser_dialect().as_ref(), &masked) {
+        Ok(_) => return Ok(()),
+        Err(ParserError::TokenizerError(message) | ParserError::ParserError(message)) => message,
+        Err(e @ ParserError::RecursionLimitExceeded) => e.to_string(),
+    };
+
+    // Errors end with " at Line: 3, Column 5" when the position is known.
+    let (message, position) = match error.rsplit_once(" at Line: ") {
+        Some((message, position)) => {
+            let position = position.split_once(", Column ").and_then(|(line, column)| {
+                Some((line.trim().parse().ok()?, column.trim().parse().ok()?))
+            });
+            (message.to_string(), position)
+        }
+        None => (error, None),
+    };
+
+    let found = message
+        .rsplit_once("found: ")
+        .map(|(_, found)| found.to_string())
+        .filter(|found| !found.is_empty() && found != "EOF");
+
+    Err(SqlError {
+        message,
+        position,
+        found,
+    })
+}
@@ -0,0 +1,354 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use error_stack::{Report, ResultExt};
+use notify_debouncer_mini::{new_debouncer, notify::RecursiveMode, DebouncedEventKind};
+
+use crate::{
+    check_output_paths, context::build_context, dependencies::template_dependencies,
+    depfile::write_depfile, diagnostics::print_status, is_template_file, print_diagnostics, prune,
+    render_templates, report_diagnostics, rust_module::write_rust_module, template_type, Error,
+    MessageFormat, Options, TemplateReport, TemplateSet, TemplateType,
+};
+
+const DEBOUNCE_TIME: Duration = Duration::from_millis(200);
+
+/// Keeps the parsed templates around between changes so that only the affected templates need to
+/// be read and rendered again.
+pub(crate) struct WatchState {
+    pub(crate) options: Options,
+    pub(crate) templates: TemplateSet,
+    pub(crate) context: tera::Context,
+    /// The most recent result of rendering each template, used to regenerate the Rust module.
+    rendered: BTreeMap<PathBuf, TemplateReport>,
+    /// The canonical paths of the input directory and the partials directories, paired with the
+    /// paths that the directory walker uses for them.
+    canonical_dirs: Vec<(PathBuf, PathBuf)>,
+}
+
+/// What happened during a single rebuild, or during the initial build.
+#[derive(Debug, Default)]
+pub(crate) struct CycleSummary {
+    /// The template files that changed. This is empty for the initial build.
+    pub(crate) changed: Vec<PathBuf>,
+    /// The templates that were rendered again.
+    pub(crate) rendered: Vec<PathBuf>,
+    pub(crate) errors: Vec<Report<Error>>,
+    pub(crate) elapsed: Duration,
+}
+
+impl CycleSummary {
+    fn print(&self, format: MessageFormat) {
+        if format == MessageFormat::Human {
+            for error in &self.errors {
+                eprintln!("{error:?}");
+            }
+        } else {
+            let diagnostics = self
+                .errors
+                .iter()
+                .flat_map(report_diagnostics)
+                .collect::<Vec<_>>();
+            print_diagnostics(format, &diagnostics);
+        }
+
+        let error_text = match self.errors.len() {
+            0 => String::new(),
+            1 => ", 1 error".to_string(),
+            n => format!(", {n} errors"),
+        };
+
+        let rendered = format!(
+            "{} in {}ms{error_text}",
+            plural(self.rendered.len(), "template"),
+            self.elapsed.as_millis()
+        );
+        let summary = if self.changed.is_empty() {
+            format!("Rendered {rendered}")
+        } else {
+            format!(
+                "{} changed, rendered {rendered}",
+                plural(self.changed.len(), "file")
+            )
+        };
+
+        print_status(format, summary);
+    }
+}
+
+fn plural(count: usize, noun: &str) -> String {
+    if count == 1 {
+        format!("1 {noun}")
+    } else {
+        format!("{count} {noun}s")
+    }
+}
+
+impl WatchState {
+    pub(crate) fn new(options: Options, templates: TemplateSet, context: tera::Context) -> Self {
+        let canonical_dirs = watched_dirs(&templates)
+            .filter_map(|dir| Some((dir.canonicalize().ok()?, dir.to_path_buf())))
+            .collect();
+        WatchState {
+            options,
+            templates,
+            context,
+            rendered: BTreeMap::new(),
+            canonical_dirs,
+        }
+    }
+
+    /// Load and render all the templates at `paths`. Errors in the templates are returned in the
+    /// summary instead of failing, so that they can be fixed while watching.
+    pub(crate) fn load(
+        options: Options,
+        input_dir: PathBuf,
+        paths: Vec<PathBuf>,
+    ) -> Result<(Self, CycleSummary), Report<Error>> {
+        let start = Instant::now();
+        // The context isn't reloaded when it changes, so an error here can't be fixed by watching.
+        let context = build_context(&options)?;
+        let (templates, errors) = TemplateSet::load_all(input_dir, &options, paths);
+        let mut state = WatchState::new(options, templates, context);
+
+        let mut summary = CycleSummary {
+            errors,
+            ..Default::default()
+        };
+        if let Err(e) = check_output_paths(&state.options, &state.templates) {
+            summary.errors.push(e);
+        }
+
+        // Templates that failed to load would only add another error for each of them.
+        let loaded = state
+            .templates
+            .normal_templates()
+            .map(|(_, name)| name)
+            .filter(|name| state.templates.tera.templates.contains_key(*name))
+            .map(|name| name.to_string())
+            .collect();
+        let prune = state.options.prune;
+        state.render(&loaded, prune, &mut summary);
+
+        summary.elapsed = start.elapsed();
+        Ok((state, summary))
+    }
+
+    /// Convert a path from the file watcher into the same form that the directory walker uses.
+    fn normalize_path(&self, path: &Path) -> PathBuf {
+        if watched_dirs(&self.templates).any(|dir| path.starts_with(dir)) {
+            return path.to_path_buf();
+        }
+
+        self.canonical_dirs
+            .iter()
+            .find_map(|(canonical, dir)| {
+                path.strip_prefix(canonical)
+                    .ok()
+                    .map(|relative| dir.join(relative))
+            })
+            .unwrap_or_else(|| path.to_path_buf())
+    }
+
+    /// The names of the normal templates which are in `names` or use any of them.
+    fn affected_templates(&self, names: &BTreeSet<String>) -> BTreeSet<String> {
+        let tera = &self.templates.tera;
+        self.templates
+            .normal_templates()
+            .filter(|(_, name)| {
+                names.contains(*name) || !template_dependencies(tera, name).is_disjoint(names)
+            })
+            .map(|(_, name)| name.to_string())
+            .collect()
+    }
+
+    /// Reload the changed files and render the templates that are affected by them.
+    pub(crate) fn apply_changes(&mut self, paths: BTreeSet<PathBuf>) -> CycleSummary {
+        let start = Instant::now();
+        let mut summary = CycleSummary::default();
+
+        let paths = paths
+            .into_iter()
+            .map(|path| self.normalize_path(&path))
+            // Only partials and macro files are loaded from the partials directories.
+            .filter(|path| {
+                !self.templates.is_shared(path) || template_type(path) != TemplateType::Normal
+            })
+            .collect::<Vec<_>>();
+
+        // Adding or removing a partial can change which file a name refers to, so remember which
+        // templates used the changed files before the change as well as after it.
+        let previous_names = paths
+            .iter()
+            .filter_map(|path| self.templates.files.get(path).cloned())
+            .collect::<BTreeSet<_>>();
+        let mut affected_names = self.affected_templates(&previous_names);
+
+        let mut changed_names = BTreeSet::new();
+        let mut removed = false;
+
+        for path in paths {
+            if path.is_file() {
+                let parsed = self.templates.register(&path).and_then(|name| {
+                    let Some(name) = name else {
+                        return Ok(None);
+                    };
+                    match self.templates.parse(&path) {
+                        Ok(()) => Ok(Some(name)),
+                        Err(e) => Err(self.templates.load_error(e)),
+                    }
+                });
+                match parsed {
+                    Ok(Some(name)) => {
+                        changed_names.insert(name);
+                    }
+                    Ok(None) => continue,
+                    Err(e) => summary.errors.push(e),
+                }
+            } else if let Some(name) = self.templates.unregister(&path) {
+                // A shared partial with the same name can take the place of a removed one.
+                if let Err(e) = self.templates.restore_shadowed(&name) {
+                    summary.errors.push(e);
+                }
+                changed_names.insert(name);
+                removed = true;
+            } else {
+                continue;
+            }
+
+            summary.changed.push(path);
+        }
+
+        if summary.changed.is_empty() {
+            return summary;
+        }
+
+        if let Err(e) = self.templates.link() {
+            summary.errors.push(e);
+        }
+
+        if let Err(e) = check_output_paths(&self.options, &self.templates) {
+            summary.errors.push(e);
+        }
+
+        affected_names.extend(self.affected_templates(&changed_names));
+        let prune = removed && self.options.prune;
+        self.render(&affected_names, prune, &mut summary);
+
+        summary.elapsed = start.elapsed();
+        summary
+    }
+
+    /// Render the templates named in `names`, and update the files generated from all of them.
+    fn render(&mut self, names: &BTreeSet<String>, prune: bool, summary: &mut CycleSummary) {
+        let templates = &self.templates;
+        let affected = templates
+            .normal_templates()
+            .filter(|(_, name)| names.contains(*name))
+            .filter(|(path, _)| path.is_file())
+            .collect::<Vec<_>>();
+
+        let results = match render_templates(&self.options, templates, &self.context, &affected) {
+            Ok(results) => results,
+            Err(e) => {
+                summary.errors.push(e);
+                Vec::new()
+            }
+        };
+
+        for result in results {
+            match result {
+                Ok(report) => {
+                    self.rendered.insert(report.template.clone(), report);
+                }
+                Err((_, e)) => summary.errors.push(e),
+            }
+        }
+
+        let templates = &self.templates;
+        self.rendered
+            .retain(|path, _| templates.files.contains_key(path));
+        let rust_module = write_rust_module(&self.options, self.rendered.values());
+        let depfile = rust_module.and_then(|rust_module| {
+            write_depfile(&self.options, self.rendered.values(), &rust_module)
+        });
+        if let Err(e) = depfile {
+            summary.errors.push(e);
+        }
+
+        if prune {
+            if let Err(e) = prune::prune(&self.options, &self.templates) {
+                summary.errors.push(e);
+            }
+        }
+
+        summary.rendered = affected
+            .into_iter()
+            .map(|(path, _)| path.to_path_buf())
+            .collect();
+    }
+}
+
+/// The input directory followed by the partials directories.
+fn watched_dirs(templates: &TemplateSet) -> impl Iterator<Item = &Path> {
+    std::iter::once(templates.input_dir.as_path())
+        .chain(templates.partials_dirs.iter().map(PathBuf::as_path))
+}
+
+/// Build the templates at `paths`, and then render them again whenever they change.
+pub(crate) fn watch(
+    options: Options,
+    input_dir: PathBuf,
+    paths: Vec<PathBuf>,
+) -> Result<(), Report<Error>> {
+    let (mut state, summary) = WatchState::load(options, input_dir, paths)?;
+    summary.print(state.options.message_format);
+    let templates = &state.templates;
+
+    let (tx, rx) = std::sync::mpsc::channel();
+    let mut debouncer = new_debouncer(DEBOUNCE_TIME, tx).change_context(Error::Watch)?;
+    for dir in watched_dirs(templates) {
+        debouncer
+            .watcher()
+            .watch(dir, RecursiveMode::Recursive)
+            .change_context(Error::Watch)
+            .attach_printable_lazy(|| dir.display().to_string())?;
+
+        print_status(
+            state.options.message_format,
+            format!("Watching {} for changes", dir.display()),
+        );
+    }
+
+    for result in rx {
+        let events = match result {
+            Ok(events) => events,
+            Err(e) => {
+                eprintln!("{:?}", Report::new(e).change_context(Error::Watch));
+                continue;
+            }
+        };
+
+        let paths = events
+            .into_iter()
+            // Wait for writes to settle instead of rendering partially written files.
+            .filter(|event| event.kind == DebouncedEventKind::Any)
+            .map(|event| event.path)
+            .filter(|path| is_template_file(path))
+            .collect::<BTreeSet<_>>();
+
+        if paths.is_empty() {
+            continue;
+        }
+
+        let summary = state.apply_changes(paths);
+        if !summary.changed.is_empty() {
+            summary.print(state.options.message_format);
+        }
+    }
+
+    Ok(())
+}